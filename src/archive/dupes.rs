@@ -0,0 +1,432 @@
+use image::imageops::FilterType;
+use image::ImageReader;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::archive::metadata::{self, FileKind, PostMetadata};
+use crate::archive::{self, escape_xml, ArchivedPost};
+use crate::cli::{DupesOpts, ReportFormat};
+use crate::config::Config;
+
+// an archived photo and its perceptual hashes
+struct Image {
+    post: usize,
+    name: String,
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    size: u64,
+    ahash: u64,
+    dhash: u64,
+    phash: u64,
+}
+
+#[derive(Serialize)]
+struct ReportImage<'a> {
+    path: String,
+    post_id: i64,
+    width: u32,
+    height: u32,
+    size: u64,
+    best: bool,
+    #[serde(skip)]
+    image: &'a Image,
+}
+
+fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// within threshold on all three hashes
+fn similar(a: &Image, b: &Image, threshold: u32) -> bool {
+    distance(a.phash, b.phash) <= threshold
+        && distance(a.ahash, b.ahash) <= threshold
+        && distance(a.dhash, b.dhash) <= threshold
+}
+
+// average hash, pixels brighter than the mean of an 8x8 thumbnail
+fn ahash(pixels: &[f64]) -> u64 {
+    let mean = pixels.iter().sum::<f64>() / pixels.len() as f64;
+    bits(pixels.iter().map(|&p| p > mean))
+}
+
+// difference hash, whether each pixel of a 9x8 thumbnail is brighter than
+// the one to its left
+fn dhash(pixels: &[f64]) -> u64 {
+    bits((0..8).flat_map(|y| (0..8).map(move |x| pixels[y * 9 + x + 1] > pixels[y * 9 + x])))
+}
+
+// perceptual hash, the lowest 8x8 frequencies of a 32x32 thumbnail compared
+// to their median
+fn phash(pixels: &[f64]) -> u64 {
+    const N: usize = 32;
+    let dct = |input: &[f64], stride: usize, offset: usize, k: usize| {
+        (0..N)
+            .map(|i| {
+                input[offset + i * stride]
+                    * (std::f64::consts::PI * (2 * i + 1) as f64 * k as f64 / (2 * N) as f64).cos()
+            })
+            .sum::<f64>()
+    };
+    // rows then the first 8 columns
+    let mut rows = vec![0f64; N * 8];
+    for y in 0..N {
+        for k in 0..8 {
+            rows[y * 8 + k] = dct(pixels, 1, y * N, k);
+        }
+    }
+    let mut coefficients = Vec::with_capacity(64);
+    for ky in 0..8 {
+        for kx in 0..8 {
+            coefficients.push(dct(&rows, 8, kx, ky));
+        }
+    }
+
+    // leave out the average brightness when taking the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = sorted[sorted.len() / 2];
+    bits(coefficients.iter().map(|&c| c > median))
+}
+
+fn bits(bits: impl Iterator<Item = bool>) -> u64 {
+    bits.fold(0, |acc, b| (acc << 1) | b as u64)
+}
+
+fn hash_image(path: &Path) -> Result<(u32, u32, [u64; 3]), String> {
+    let image = ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .map_err(|e| e.to_string())?
+        .decode()
+        .map_err(|e| e.to_string())?;
+    let gray = |w, h| {
+        image
+            .resize_exact(w, h, FilterType::Triangle)
+            .to_luma8()
+            .pixels()
+            .map(|p| p.0[0] as f64)
+            .collect::<Vec<_>>()
+    };
+    let hashes = [ahash(&gray(8, 8)), dhash(&gray(9, 8)), phash(&gray(32, 32))];
+    Ok((image.width(), image.height(), hashes))
+}
+
+// photos in a post, from its metadata or by name for legacy posts
+fn photo_names(post: &ArchivedPost, metadata: Option<&PostMetadata>) -> Vec<String> {
+    match metadata {
+        Some(m) => m
+            .files
+            .iter()
+            .filter(|f| f.kind == FileKind::Photo && f.duplicate_of.is_none())
+            .map(|f| f.name.clone())
+            .collect(),
+        None => fs::read_dir(&post.dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .filter(|name| name.contains("-img"))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+fn hash_images(files: Vec<(usize, String, PathBuf)>) -> Vec<Image> {
+    archive::par_map(&files, |(post, name, path)| {
+        let size = fs::metadata(path).map_or(0, |m| m.len());
+        match hash_image(path) {
+            Ok((width, height, [ahash, dhash, phash])) => Some(Image {
+                post: *post,
+                name: name.clone(),
+                path: path.clone(),
+                width,
+                height,
+                size,
+                ahash,
+                dhash,
+                phash,
+            }),
+            Err(e) => {
+                eprintln!("Error hashing {}: {}", path.display(), e);
+                None
+            }
+        }
+    })
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+// BK-tree over pHash distances, to find candidates without comparing every
+// pair of images. Images with the same hash share a node
+struct BkNode {
+    hash: u64,
+    images: Vec<usize>,
+    children: HashMap<u32, BkNode>,
+}
+
+impl BkNode {
+    fn new(hash: u64, image: usize) -> Self {
+        BkNode {
+            hash,
+            images: vec![image],
+            children: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, hash: u64, image: usize) {
+        let d = distance(self.hash, hash);
+        if d == 0 {
+            self.images.push(image);
+            return;
+        }
+        match self.children.get_mut(&d) {
+            Some(child) => child.insert(hash, image),
+            None => {
+                self.children.insert(d, BkNode::new(hash, image));
+            }
+        }
+    }
+
+    fn find(&self, hash: u64, max: u32, found: &mut Vec<usize>) {
+        let d = distance(self.hash, hash);
+        if d <= max {
+            found.extend(&self.images);
+        }
+        for (&child_d, child) in &self.children {
+            if child_d + max >= d && child_d <= d + max {
+                child.find(hash, max, found);
+            }
+        }
+    }
+}
+
+fn find_root(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    root
+}
+
+// groups of near-duplicate images, largest first
+fn cluster(images: &[Image], threshold: u32) -> Vec<Vec<usize>> {
+    let mut tree: Option<BkNode> = None;
+    let mut parents: Vec<usize> = (0..images.len()).collect();
+    for (i, image) in images.iter().enumerate() {
+        match &mut tree {
+            Some(root) => {
+                let mut found = Vec::new();
+                root.find(image.phash, threshold, &mut found);
+                for j in found {
+                    if similar(image, &images[j], threshold) {
+                        let (a, b) = (find_root(&mut parents, i), find_root(&mut parents, j));
+                        parents[a] = b;
+                    }
+                }
+                root.insert(image.phash, i);
+            }
+            None => tree = Some(BkNode::new(image.phash, i)),
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..images.len() {
+        groups
+            .entry(find_root(&mut parents, i))
+            .or_default()
+            .push(i);
+    }
+    let mut clusters: Vec<Vec<usize>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    clusters.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+    clusters
+}
+
+fn write_report(report: &[Vec<ReportImage>], format: ReportFormat) -> Result<String, String> {
+    let mut out = String::new();
+    match format {
+        ReportFormat::Text => {
+            for (i, cluster) in report.iter().enumerate() {
+                out += &format!("Cluster {} ({} images):\n", i + 1, cluster.len());
+                for image in cluster {
+                    out += &format!(
+                        "  {} {:>5}x{:<5} {:>10} bytes  {}\n",
+                        if image.best { "*" } else { " " },
+                        image.width,
+                        image.height,
+                        image.size,
+                        image.path
+                    );
+                }
+            }
+        }
+        ReportFormat::Json => {
+            out = serde_json::to_string_pretty(report).map_err(|e| e.to_string())?;
+            out.push('\n');
+        }
+        ReportFormat::Html => {
+            out += "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                    <title>Near-duplicate images</title>\n\
+                    <style>img { max-height: 200px; } .best { outline: 4px solid green; }</style>\n\
+                    </head>\n<body>\n";
+            for (i, cluster) in report.iter().enumerate() {
+                out += &format!(
+                    "<h2>Cluster {} ({} images)</h2>\n<div>\n",
+                    i + 1,
+                    cluster.len()
+                );
+                for image in cluster {
+                    // absolute paths so the report works wherever it is saved
+                    let path = fs::canonicalize(&image.image.path)
+                        .map_or_else(|_| image.path.clone(), |p| p.display().to_string());
+                    let path = escape_xml(&path);
+                    out += &format!(
+                        "<figure{}><a href=\"{}\"><img src=\"{}\" loading=\"lazy\"></a>\
+                         <figcaption>{}x{}, {} bytes<br>{}</figcaption></figure>\n",
+                        if image.best { " class=\"best\"" } else { "" },
+                        path,
+                        path,
+                        image.width,
+                        image.height,
+                        image.size,
+                        escape_xml(&image.path)
+                    );
+                }
+                out += "</div>\n";
+            }
+            out += "</body>\n</html>\n";
+        }
+    }
+    Ok(out)
+}
+
+// remove every copy but the best of each cluster, marking them as duplicates
+// in the post metadata so verify and repair leave them alone. Clusters can
+// chain images that are each close to the next, so only images close to the
+// best one itself are removed
+fn keep_best(
+    posts: &mut [(ArchivedPost, Option<PostMetadata>)],
+    report: &[Vec<ReportImage>],
+    threshold: u32,
+) {
+    let mut removed = 0usize;
+    let mut changed = Vec::new();
+    for cluster in report {
+        let best = match cluster.iter().find(|i| i.best) {
+            Some(b) => b,
+            None => continue,
+        };
+        for image in cluster.iter().filter(|i| !i.best) {
+            if !similar(image.image, best.image, threshold) {
+                eprintln!("Keeping {} (not close enough to {})", image.path, best.path);
+                continue;
+            }
+            let metadata = &mut posts[image.image.post].1;
+            let file = metadata.as_mut().and_then(|m| {
+                m.files
+                    .iter_mut()
+                    .find(|f| f.kind == FileKind::Photo && f.name == image.image.name)
+            });
+            let file = match file {
+                Some(f) => f,
+                None => {
+                    eprintln!("Keeping {} (no metadata)", image.path);
+                    continue;
+                }
+            };
+            if let Err(e) = fs::remove_file(&image.image.path) {
+                eprintln!("Error removing {}: {}", image.path, e);
+                continue;
+            }
+            file.duplicate_of = Some(best.path.clone());
+            removed += 1;
+            changed.push(image.image.post);
+        }
+    }
+
+    changed.sort_unstable();
+    changed.dedup();
+    for i in changed {
+        let (post, metadata) = &posts[i];
+        if let Some(m) = metadata {
+            if let Err(e) = metadata::write(post.metadata_path(), m) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+    eprintln!("Removed {} lower resolution duplicates", removed);
+}
+
+/// Report clusters of near-duplicate photos across the whole archive
+pub fn dupes(conf: &Config, opts: &DupesOpts) -> Result<(), String> {
+    let mut posts: Vec<(ArchivedPost, Option<PostMetadata>)> = archive::all_posts(conf)
+        .into_iter()
+        .map(|post| {
+            let metadata = post.read_metadata().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                None
+            });
+            (post, metadata)
+        })
+        .collect();
+
+    let files = posts
+        .iter()
+        .enumerate()
+        .flat_map(|(i, (post, metadata))| {
+            photo_names(post, metadata.as_ref())
+                .into_iter()
+                .map(move |name| (i, name.clone(), post.dir.join(name)))
+        })
+        .collect();
+    let images = hash_images(files);
+
+    let report: Vec<Vec<ReportImage>> = cluster(&images, opts.threshold)
+        .into_iter()
+        .map(|cluster| {
+            // the highest resolution, then largest, copy is the best
+            let best = *cluster
+                .iter()
+                .max_by_key(|&&i| {
+                    let image = &images[i];
+                    (image.width as u64 * image.height as u64, image.size)
+                })
+                .unwrap();
+            cluster
+                .into_iter()
+                .map(|i| {
+                    let image = &images[i];
+                    ReportImage {
+                        path: image.path.display().to_string(),
+                        post_id: posts[image.post].0.id,
+                        width: image.width,
+                        height: image.height,
+                        size: image.size,
+                        best: i == best,
+                        image,
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    let out = write_report(&report, opts.format)?;
+    match &opts.output {
+        Some(path) => fs::write(path, out).map_err(|e| format!("{}: {}", path.display(), e))?,
+        None => print!("{}", out),
+    }
+    eprintln!(
+        "Found {} clusters of near-duplicates among {} photos",
+        report.len(),
+        images.len()
+    );
+
+    if opts.keep_best {
+        keep_best(&mut posts, &report, opts.threshold);
+    }
+    Ok(())
+}
@@ -0,0 +1,450 @@
+use chrono::{DateTime, FixedOffset};
+use std::fs;
+use std::path::Path;
+
+use crate::archive::escape_xml;
+use crate::archive::media::{self, Format};
+
+// longest description written, keeping metadata segments well under the
+// 64KB limit of a JPEG segment
+const MAX_DESCRIPTION_LEN: usize = 8192;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+
+/// Post details written into an image
+pub struct Tags<'a> {
+    pub date: Option<DateTime<FixedOffset>>,
+    pub artist: &'a str,
+    pub description: &'a str,
+    pub source: &'a str,
+}
+
+enum Value {
+    Ascii(Vec<u8>),
+    Long(u32),
+}
+
+/// Write EXIF and XMP tags into a JPEG, PNG or WebP file, replacing any that
+/// are already there. Returns false for other formats
+pub fn embed(path: impl AsRef<Path>, tags: &Tags) -> Result<bool, String> {
+    let path = path.as_ref();
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let exif = exif(tags);
+    let xmp = xmp(tags);
+
+    let embedded = match media::sniff(&data) {
+        Some(Format::Jpeg) => embed_jpeg(&data, &exif, &xmp),
+        Some(Format::Png) => embed_png(&data, &exif, &xmp),
+        Some(Format::Webp) => embed_webp(&data, &exif, &xmp),
+        _ => return Ok(false),
+    }
+    .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let temp_path = path.with_extension("embed");
+    fs::write(&temp_path, embedded)
+        .and_then(|_| fs::rename(&temp_path, path))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(true)
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+// a little endian TIFF structure with the IFD0 and Exif IFD tags
+fn exif(tags: &Tags) -> Vec<u8> {
+    let ascii = |s: &str| Value::Ascii(s.as_bytes().to_vec());
+    let mut ifd0 = vec![
+        (
+            0x010E,
+            ascii(truncate(tags.description, MAX_DESCRIPTION_LEN)),
+        ),
+        (0x013B, ascii(tags.artist)),
+    ];
+    let mut exif_ifd = Vec::new();
+    if let Some(date) = tags.date {
+        exif_ifd.push((0x9003, ascii(&date.format("%Y:%m:%d %H:%M:%S").to_string())));
+        exif_ifd.push((0x9011, ascii(&date.format("%:z").to_string())));
+    }
+
+    let mut tiff = b"II\x2A\x00\x08\x00\x00\x00".to_vec();
+    if exif_ifd.is_empty() {
+        tiff.extend(ifd(8, &ifd0));
+    } else {
+        // the length of IFD0 doesn't depend on where the Exif IFD goes
+        ifd0.push((0x8769, Value::Long(0)));
+        let exif_offset = 8 + ifd(8, &ifd0).len() as u32;
+        ifd0.pop();
+        ifd0.push((0x8769, Value::Long(exif_offset)));
+        tiff.extend(ifd(8, &ifd0));
+        tiff.extend(ifd(exif_offset, &exif_ifd));
+    }
+    tiff
+}
+
+// an IFD at offset in the TIFF structure, followed by the values that don't
+// fit in its entries
+fn ifd(offset: u32, entries: &[(u16, Value)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut values = Vec::new();
+    let values_offset = offset + 2 + 12 * entries.len() as u32 + 4;
+
+    out.extend(&(entries.len() as u16).to_le_bytes());
+    for (tag, value) in entries {
+        out.extend(&tag.to_le_bytes());
+        match value {
+            Value::Ascii(s) => {
+                let mut s = s.clone();
+                s.push(0);
+                out.extend(&2u16.to_le_bytes());
+                out.extend(&(s.len() as u32).to_le_bytes());
+                if s.len() <= 4 {
+                    s.resize(4, 0);
+                    out.extend(&s);
+                } else {
+                    out.extend(&(values_offset + values.len() as u32).to_le_bytes());
+                    values.extend(&s);
+                    if values.len() % 2 == 1 {
+                        values.push(0);
+                    }
+                }
+            }
+            Value::Long(n) => {
+                out.extend(&4u16.to_le_bytes());
+                out.extend(&1u32.to_le_bytes());
+                out.extend(&n.to_le_bytes());
+            }
+        }
+    }
+    out.extend(&0u32.to_le_bytes());
+    out.extend(values);
+    out
+}
+
+fn xmp(tags: &Tags) -> Vec<u8> {
+    let date = tags
+        .date
+        .map(|d| d.format("%Y-%m-%dT%H:%M:%S%:z").to_string())
+        .map(|d| {
+            format!(
+                "\n   <photoshop:DateCreated>{0}</photoshop:DateCreated>\
+                 \n   <exif:DateTimeOriginal>{0}</exif:DateTimeOriginal>",
+                d
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n \
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n  \
+         <rdf:Description rdf:about=\"\"\n    \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n    \
+         xmlns:exif=\"http://ns.adobe.com/exif/1.0/\"\n    \
+         xmlns:photoshop=\"http://ns.adobe.com/photoshop/1.0/\">\n   \
+         <dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>\n   \
+         <dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>\n   \
+         <dc:source>{}</dc:source>{}\n  \
+         </rdf:Description>\n \
+         </rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>",
+        escape_xml(tags.artist),
+        escape_xml(truncate(tags.description, MAX_DESCRIPTION_LEN)),
+        escape_xml(tags.source),
+        date
+    )
+    .into_bytes()
+}
+
+fn jpeg_segment(marker: u8, header: &[u8], payload: &[u8]) -> Result<Vec<u8>, String> {
+    let len = 2 + header.len() + payload.len();
+    if len > u16::MAX as usize {
+        return Err(String::from("metadata too large for a JPEG segment"));
+    }
+    let mut out = vec![0xFF, marker];
+    out.extend(&(len as u16).to_be_bytes());
+    out.extend(header);
+    out.extend(payload);
+    Ok(out)
+}
+
+// insert APP1 segments after any leading APP0 segments, dropping existing
+// Exif and XMP segments
+fn embed_jpeg(data: &[u8], exif: &[u8], xmp: &[u8]) -> Result<Vec<u8>, String> {
+    let malformed = || String::from("malformed JPEG");
+    let mut leading: Vec<u8> = Vec::new();
+    let mut rest: Vec<u8> = Vec::new();
+    let mut i = 2;
+    loop {
+        while data.get(i + 1) == Some(&0xFF) && data[i] == 0xFF {
+            i += 1;
+        }
+        if data.get(i) != Some(&0xFF) {
+            return Err(malformed());
+        }
+        let marker = *data.get(i + 1).ok_or_else(malformed)?;
+        if marker == 0xDA || marker == 0xD9 {
+            // entropy coded data follows, copy everything from here on
+            rest.extend(&data[i..]);
+            break;
+        }
+        let len = match marker {
+            0xD0..=0xD7 | 0x01 => 0,
+            _ => {
+                let b = data.get(i + 2..i + 4).ok_or_else(malformed)?;
+                u16::from_be_bytes([b[0], b[1]]) as usize
+            }
+        };
+        let segment = data.get(i..i + 2 + len).ok_or_else(malformed)?;
+        let payload = segment.get(4..).unwrap_or_default();
+        let replaced =
+            marker == 0xE1 && (payload.starts_with(EXIF_HEADER) || payload.starts_with(XMP_HEADER));
+        if marker == 0xE0 && rest.is_empty() {
+            leading.extend(segment);
+        } else if !replaced {
+            rest.extend(segment);
+        }
+        i += 2 + len;
+    }
+
+    let mut out = data[..2].to_vec();
+    out.extend(leading);
+    out.extend(jpeg_segment(0xE1, EXIF_HEADER, exif)?);
+    out.extend(jpeg_segment(0xE1, XMP_HEADER, xmp)?);
+    out.extend(rest);
+    Ok(out)
+}
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in parts.iter().flat_map(|p| p.iter()) {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut out = (data.len() as u32).to_be_bytes().to_vec();
+    out.extend(kind);
+    out.extend(data);
+    out.extend(&crc32(&[kind, data]).to_be_bytes());
+    out
+}
+
+// insert eXIf and iTXt chunks after IHDR, dropping existing ones
+fn embed_png(data: &[u8], exif: &[u8], xmp: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = data[..8].to_vec();
+    let mut i = 8;
+    while i < data.len() {
+        let header = data
+            .get(i..i + 8)
+            .ok_or_else(|| String::from("malformed PNG"))?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = &header[4..8];
+        let chunk = data
+            .get(i..i + 12 + len)
+            .ok_or_else(|| String::from("malformed PNG"))?;
+        let replaced = kind == b"eXIf" || (kind == b"iTXt" && chunk[8..].starts_with(XMP_KEYWORD));
+        if !replaced {
+            out.extend(chunk);
+        }
+        if kind == b"IHDR" {
+            out.extend(png_chunk(b"eXIf", exif));
+            let mut itxt = XMP_KEYWORD.to_vec();
+            itxt.extend(b"\0\0\0\0\0");
+            itxt.extend(xmp);
+            out.extend(png_chunk(b"iTXt", &itxt));
+        }
+        i += 12 + len;
+    }
+    Ok(out)
+}
+
+fn webp_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut out = kind.to_vec();
+    out.extend(&(data.len() as u32).to_le_bytes());
+    out.extend(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+    out
+}
+
+// use the extended format and append EXIF and XMP chunks, dropping
+// existing ones
+fn embed_webp(data: &[u8], exif: &[u8], xmp: &[u8]) -> Result<Vec<u8>, String> {
+    const ALPHA: u8 = 0x10;
+    const EXIF: u8 = 0x08;
+    const XMP: u8 = 0x04;
+
+    let mut chunks: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    let mut i = 12;
+    while i + 8 <= data.len() {
+        let kind = data[i..i + 4].to_vec();
+        let len = u32::from_le_bytes([data[i + 4], data[i + 5], data[i + 6], data[i + 7]]) as usize;
+        let body = data
+            .get(i + 8..i + 8 + len)
+            .ok_or_else(|| String::from("malformed WebP"))?;
+        if kind != b"EXIF" && kind != b"XMP " {
+            chunks.push((kind, body.to_vec()));
+        }
+        i += 8 + len + len % 2;
+    }
+
+    match chunks.first_mut() {
+        Some((kind, body)) if kind == b"VP8X" && !body.is_empty() => body[0] |= EXIF | XMP,
+        Some((kind, body)) => {
+            let (width, height) = media::dimensions(data)
+                .filter(|&(w, h)| w > 0 && h > 0)
+                .ok_or_else(|| String::from("malformed WebP"))?;
+            let alpha = kind == b"VP8L" && body.get(4).is_some_and(|b| b & 0x10 != 0);
+            let mut vp8x = vec![EXIF | XMP | if alpha { ALPHA } else { 0 }, 0, 0, 0];
+            vp8x.extend(&(width - 1).to_le_bytes()[..3]);
+            vp8x.extend(&(height - 1).to_le_bytes()[..3]);
+            chunks.insert(0, (b"VP8X".to_vec(), vp8x));
+        }
+        None => return Err(String::from("malformed WebP")),
+    }
+    chunks.push((b"EXIF".to_vec(), exif.to_vec()));
+    chunks.push((b"XMP ".to_vec(), xmp.to_vec()));
+
+    let body: Vec<u8> = chunks
+        .iter()
+        .flat_map(|(kind, data)| webp_chunk(kind, data))
+        .collect();
+    let mut out = b"RIFF".to_vec();
+    out.extend(&(4 + body.len() as u32).to_le_bytes());
+    out.extend(b"WEBP");
+    out.extend(body);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+    use std::convert::TryInto;
+    use std::io::Cursor;
+
+    // 1x1 lossy and lossless WebP images
+    const LOSSY_WEBP: &[u8] = b"RIFF\x22\0\0\0WEBPVP8 \x16\0\0\0\x30\x01\0\x9D\x01\x2A\x01\0\x01\0\
+        \x0E\xC0\xFE\x25\xA4\0\x03\x70\0\0\0\0";
+    const LOSSLESS_WEBP: &[u8] = b"RIFF\x1A\0\0\0WEBPVP8L\x0D\0\0\0\x2F\0\0\0\x10\x07\x10\x11\x11\
+        \x88\x88\xFE\x07\0";
+
+    fn tags() -> Tags<'static> {
+        Tags {
+            date: DateTime::parse_from_rfc3339("2021-06-13T12:00:00+09:00").ok(),
+            artist: "RM",
+            description: "Hello <ARMY> & \u{1F49C}",
+            source: "https://weverse.io/bts/artist/0-123",
+        }
+    }
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        RgbImage::from_pixel(3, 2, image::Rgb([200, 100, 50]))
+            .write_to(&mut data, format)
+            .unwrap();
+        data.into_inner()
+    }
+
+    // embed into a copy of the image on disk, check it still decodes and
+    // validates, and return the tagged bytes
+    fn round_trip(name: &str, data: &[u8], format: ImageFormat) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("embed-{}-{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        assert!(embed(&path, &tags()).unwrap());
+        let tagged = fs::read(&path).unwrap();
+        let original = image::load_from_memory_with_format(data, format).unwrap();
+        let decoded = image::load_from_memory_with_format(&tagged, format).unwrap();
+        assert_eq!(decoded.to_rgba8(), original.to_rgba8());
+        media::validate(&path).unwrap();
+
+        // embedding again replaces the tags instead of adding more
+        assert!(embed(&path, &tags()).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), tagged);
+        fs::remove_file(&path).unwrap();
+        tagged
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn jpeg_round_trip() {
+        let tagged = round_trip("jpeg", &encode(ImageFormat::Jpeg), ImageFormat::Jpeg);
+        assert!(contains(&tagged, EXIF_HEADER));
+        assert!(contains(&tagged, XMP_HEADER));
+        assert!(contains(&tagged, b"2021:06:13 12:00:00"));
+        assert!(contains(&tagged, b"Hello &lt;ARMY&gt; &amp;"));
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let tagged = round_trip("png", &encode(ImageFormat::Png), ImageFormat::Png);
+        assert!(contains(&tagged, b"eXIf"));
+        assert!(contains(&tagged, XMP_KEYWORD));
+
+        // every chunk has a valid checksum
+        let mut i = 8;
+        while i < tagged.len() {
+            let len = u32::from_be_bytes(tagged[i..i + 4].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(tagged[i + 8 + len..i + 12 + len].try_into().unwrap());
+            assert_eq!(crc32(&[&tagged[i + 4..i + 8 + len]]), crc);
+            i += 12 + len;
+        }
+        assert_eq!(i, tagged.len());
+    }
+
+    #[test]
+    fn lossy_webp_round_trip() {
+        let tagged = round_trip("lossy.webp", LOSSY_WEBP, ImageFormat::WebP);
+        assert_eq!(&tagged[12..16], b"VP8X");
+        assert!(contains(&tagged, b"EXIF"));
+        assert!(contains(&tagged, b"XMP "));
+        assert_eq!(media::dimensions(&tagged), Some((1, 1)));
+    }
+
+    #[test]
+    fn lossless_webp_round_trip() {
+        let tagged = round_trip("lossless.webp", LOSSLESS_WEBP, ImageFormat::WebP);
+        assert_eq!(&tagged[12..16], b"VP8X");
+        assert!(contains(&tagged, b"EXIF"));
+        assert_eq!(media::dimensions(&tagged), Some((1, 1)));
+    }
+
+    #[test]
+    fn webp_without_dimensions_is_malformed() {
+        let mut data = LOSSY_WEBP.to_vec();
+        // zero width in the VP8 frame header
+        data[26] = 0;
+        data[27] = 0;
+        assert_eq!(
+            embed_webp(&data, b"", b""),
+            Err(String::from("malformed WebP"))
+        );
+    }
+
+    #[test]
+    fn crc32_matches_png() {
+        assert_eq!(crc32(&[b"IEND"]), 0xAE42_6082);
+    }
+}
@@ -0,0 +1,281 @@
+use rusqlite::{params, Connection};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::archive::metadata::PostMetadata;
+use crate::archive::{self, ArchivedPost};
+use crate::cli::{ExportFormat, ExportOpts};
+use crate::config::Config;
+
+const DEFAULT_SQLITE_PATH: &str = "weverse.sqlite";
+
+const CSV_COLUMNS: &[&str] = &[
+    "id",
+    "artist",
+    "member",
+    "type",
+    "created_at",
+    "updated_at",
+    "body_length",
+    "photos",
+    "videos",
+    "locked",
+    "comment_count",
+    "archived_at",
+    "deleted_upstream_at",
+    "dir",
+];
+
+const SCHEMA: &str = "
+CREATE TABLE artists (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL
+);
+CREATE TABLE members (
+    id INTEGER PRIMARY KEY,
+    artist_id INTEGER NOT NULL REFERENCES artists(id),
+    nickname TEXT NOT NULL
+);
+CREATE TABLE posts (
+    id INTEGER PRIMARY KEY,
+    artist_id INTEGER NOT NULL REFERENCES artists(id),
+    member_id INTEGER NOT NULL REFERENCES members(id),
+    type TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT,
+    body TEXT,
+    body_length INTEGER NOT NULL,
+    photos INTEGER NOT NULL,
+    videos INTEGER NOT NULL,
+    locked INTEGER NOT NULL,
+    comment_count INTEGER,
+    archived_at TEXT NOT NULL,
+    deleted_upstream_at TEXT,
+    dir TEXT NOT NULL
+);
+CREATE TABLE files (
+    post_id INTEGER NOT NULL REFERENCES posts(id),
+    kind TEXT NOT NULL,
+    idx INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    sha256 TEXT NOT NULL,
+    width INTEGER,
+    height INTEGER,
+    duration REAL,
+    PRIMARY KEY (post_id, kind, idx)
+);
+CREATE INDEX posts_artist_created ON posts (artist_id, created_at);
+CREATE INDEX posts_member_created ON posts (member_id, created_at);
+";
+
+// the values shared by both formats
+struct Row<'a> {
+    metadata: &'a PostMetadata,
+    post_type: String,
+    body_length: usize,
+    photos: usize,
+    videos: usize,
+    dir: String,
+}
+
+impl<'a> Row<'a> {
+    fn new(archived: &ArchivedPost, metadata: &'a PostMetadata) -> Self {
+        let post = &metadata.post;
+        Row {
+            metadata,
+            // unknown types are exported as weverse names them
+            post_type: post
+                .kind()
+                .map_or_else(|_| post.post_type.to_lowercase(), |t| t.name().to_string()),
+            body_length: post.body.as_deref().map_or(0, |b| b.chars().count()),
+            photos: post.photos.as_ref().map_or(0, Vec::len),
+            videos: post.attached_videos.as_ref().map_or(0, Vec::len),
+            dir: archived.dir.display().to_string(),
+        }
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn write_csv(rows: &[Row]) -> String {
+    let mut out = CSV_COLUMNS.join(",") + "\n";
+    for row in rows {
+        let post = &row.metadata.post;
+        let fields = [
+            post.id.to_string(),
+            post.community.name.to_lowercase(),
+            post.community_user.nickname.clone(),
+            row.post_type.to_string(),
+            post.created_at.clone(),
+            post.updated_at.clone(),
+            row.body_length.to_string(),
+            row.photos.to_string(),
+            row.videos.to_string(),
+            post.locked.to_string(),
+            post.comment_count
+                .map_or_else(String::new, |c| c.to_string()),
+            row.metadata.archived_at.clone(),
+            row.metadata.deleted_upstream_at.clone().unwrap_or_default(),
+            row.dir.clone(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        out += &fields.join(",");
+        out.push('\n');
+    }
+    out
+}
+
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+fn is_sqlite(path: &Path) -> bool {
+    let mut header = [0; SQLITE_HEADER.len()];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .is_ok()
+        && header == SQLITE_HEADER
+}
+
+fn write_sqlite(rows: &[Row], path: &Path) -> rusqlite::Result<()> {
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    let tx = conn.transaction()?;
+    {
+        let mut artist = tx.prepare("INSERT OR IGNORE INTO artists (id, name) VALUES (?1, ?2)")?;
+        let mut member = tx.prepare(
+            "INSERT OR REPLACE INTO members (id, artist_id, nickname) VALUES (?1, ?2, ?3)",
+        )?;
+        let mut post_stmt = tx.prepare(
+            "INSERT OR REPLACE INTO posts (id, artist_id, member_id, type, created_at, updated_at,
+                body, body_length, photos, videos, locked, comment_count, archived_at,
+                deleted_upstream_at, dir)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+        )?;
+        let mut file_stmt = tx.prepare(
+            "INSERT OR REPLACE INTO files (post_id, kind, idx, name, size, sha256, width, height,
+                duration)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+
+        for row in rows {
+            let metadata = row.metadata;
+            let post = &metadata.post;
+            artist.execute(params![
+                post.community.id,
+                post.community.name.to_lowercase()
+            ])?;
+            member.execute(params![
+                post.community_user.id,
+                post.community.id,
+                post.community_user.nickname
+            ])?;
+            post_stmt.execute(params![
+                post.id,
+                post.community.id,
+                post.community_user.id,
+                row.post_type.as_str(),
+                post.created_at,
+                Some(&post.updated_at).filter(|u| !u.is_empty()),
+                post.body,
+                row.body_length as i64,
+                row.photos as i64,
+                row.videos as i64,
+                post.locked,
+                post.comment_count,
+                metadata.archived_at,
+                metadata.deleted_upstream_at,
+                row.dir,
+            ])?;
+            for file in &metadata.files {
+                let kind = serde_json::to_value(file.kind)
+                    .ok()
+                    .and_then(|v| v.as_str().map(String::from))
+                    .unwrap_or_default();
+                file_stmt.execute(params![
+                    post.id,
+                    kind,
+                    file.index as i64,
+                    file.name,
+                    file.size as i64,
+                    file.sha256,
+                    file.width,
+                    file.height,
+                    file.duration,
+                ])?;
+            }
+        }
+    }
+    tx.commit()
+}
+
+/// Export the metadata of every archived post as CSV or SQLite
+pub fn export(conf: &Config, opts: &ExportOpts) -> Result<(), String> {
+    let mut skipped = 0usize;
+    let posts: Vec<(ArchivedPost, PostMetadata)> = archive::all_posts(conf)
+        .into_iter()
+        .filter_map(|post| match post.read_metadata() {
+            Ok(Some(m)) => Some((post, m)),
+            Ok(None) => {
+                skipped += 1;
+                None
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                skipped += 1;
+                None
+            }
+        })
+        .collect();
+    let rows: Vec<Row> = posts.iter().map(|(p, m)| Row::new(p, m)).collect();
+
+    match opts.format {
+        ExportFormat::Csv => {
+            let csv = write_csv(&rows);
+            match &opts.output {
+                Some(path) => {
+                    fs::write(path, csv).map_err(|e| format!("{}: {}", path.display(), e))?
+                }
+                None => print!("{}", csv),
+            }
+        }
+        ExportFormat::Sqlite => {
+            let path = opts
+                .output
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SQLITE_PATH.into());
+            if !opts.force && path.exists() && !is_sqlite(&path) {
+                return Err(format!(
+                    "{} exists and is not a SQLite database, use --force to replace it",
+                    path.display()
+                ));
+            }
+            // each export is a fresh snapshot of the archive, only put in
+            // place of the old one once it is complete
+            let mut part_path = path.clone().into_os_string();
+            part_path.push(".part");
+            let part_path = PathBuf::from(part_path);
+            let _ = fs::remove_file(&part_path);
+            write_sqlite(&rows, &part_path)
+                .map_err(|e| e.to_string())
+                .and_then(|_| fs::rename(&part_path, &path).map_err(|e| e.to_string()))
+                .map_err(|e| {
+                    let _ = fs::remove_file(&part_path);
+                    format!("{}: {}", path.display(), e)
+                })?;
+            eprintln!("Wrote {}", path.display());
+        }
+    }
+    eprintln!(
+        "Exported {} posts, skipped {} without metadata",
+        rows.len(),
+        skipped
+    );
+    Ok(())
+}
@@ -0,0 +1,310 @@
+use chrono::DateTime;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::archive::metadata::{MediaFile, PostMetadata};
+use crate::archive::{self, escape_xml, ArchivedPost};
+use crate::config::{Config, FeedFormat, FeedsConfig};
+use crate::network::network_structs::PostType;
+use crate::network::{get_download_dir, get_url};
+
+const DEFAULT_FEEDS_PATH: &str = "feeds";
+const DEFAULT_MAX_ENTRIES: usize = 50;
+const TITLE_LEN: usize = 80;
+
+// percent encode a relative path for use in a url
+fn encode_path(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut out = String::new();
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            _ => out += &format!("%{:02X}", b),
+        }
+    }
+    out
+}
+
+// path of `to` relative to the directory `from`
+fn relative_path(from: &Path, to: &Path) -> Option<PathBuf> {
+    let from = fs::canonicalize(from).ok()?;
+    let to = fs::canonicalize(to).ok()?;
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &to[common..] {
+        path.push(component);
+    }
+    Some(path)
+}
+
+fn mime_type(name: &str) -> &'static str {
+    match Path::new(name).extension().and_then(|e| e.to_str()) {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("mp4") => "video/mp4",
+        Some("ts") => "video/mp2t",
+        _ => "application/octet-stream",
+    }
+}
+
+// the first line of the post body, shortened
+fn title(metadata: &PostMetadata) -> String {
+    let post = &metadata.post;
+    let line = post
+        .body
+        .as_deref()
+        .and_then(|b| b.lines().find(|l| !l.trim().is_empty()))
+        .unwrap_or_default()
+        .trim();
+    let line = match line.char_indices().nth(TITLE_LEN) {
+        Some((i, _)) => format!("{}…", &line[..i]),
+        None => line.to_string(),
+    };
+    if line.is_empty() {
+        post.community_user.nickname.clone()
+    } else {
+        format!("{}: {}", post.community_user.nickname, line)
+    }
+}
+
+struct Feed<'a> {
+    config: &'a FeedsConfig,
+    dir: PathBuf,
+    cwd: PathBuf,
+}
+
+impl Feed<'_> {
+    // link to an archived media file, under base_url if set or relative to
+    // the feed otherwise
+    fn media_url(&self, post: &ArchivedPost, file: &MediaFile) -> Option<String> {
+        let path = post.dir.join(&file.name);
+        match &self.config.base_url {
+            Some(base) => Some(format!(
+                "{}/{}",
+                base.trim_end_matches('/'),
+                encode_path(&relative_path(&self.cwd, &path)?)
+            )),
+            None => Some(encode_path(&relative_path(&self.dir, &path)?)),
+        }
+    }
+
+    fn atom(
+        &self,
+        artist: &str,
+        post_type: PostType,
+        posts: &[(ArchivedPost, PostMetadata)],
+    ) -> String {
+        let updated = posts
+            .first()
+            .map_or("", |(_, m)| m.post.created_at.as_str());
+        let mut out = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <feed xmlns=\"http://www.w3.org/2005/Atom\">\n  \
+             <id>urn:download-weverse:{0}:{1}</id>\n  \
+             <title>{0} {1}</title>\n  \
+             <link rel=\"alternate\" href=\"https://weverse.io/{0}\"/>\n  \
+             <updated>{2}</updated>\n",
+            escape_xml(artist),
+            post_type.name(),
+            escape_xml(updated)
+        );
+        for (archived, metadata) in posts {
+            let post = &metadata.post;
+            let url = escape_xml(&get_url(post));
+            let updated = if post.updated_at.is_empty() {
+                &post.created_at
+            } else {
+                &post.updated_at
+            };
+            out += &format!(
+                "  <entry>\n    \
+                 <id>{0}</id>\n    \
+                 <title>{1}</title>\n    \
+                 <link rel=\"alternate\" href=\"{0}\"/>\n    \
+                 <author><name>{2}</name></author>\n    \
+                 <published>{3}</published>\n    \
+                 <updated>{4}</updated>\n    \
+                 <content type=\"text\">{5}</content>\n",
+                url,
+                escape_xml(&title(metadata)),
+                escape_xml(&post.community_user.nickname),
+                escape_xml(&post.created_at),
+                escape_xml(updated),
+                escape_xml(post.body.as_deref().unwrap_or_default())
+            );
+            for file in &metadata.files {
+                if let Some(href) = self.media_url(archived, file) {
+                    out += &format!(
+                        "    <link rel=\"enclosure\" href=\"{}\" type=\"{}\" length=\"{}\"/>\n",
+                        escape_xml(&href),
+                        mime_type(&file.name),
+                        file.size
+                    );
+                }
+            }
+            out += "  </entry>\n";
+        }
+        out += "</feed>\n";
+        out
+    }
+
+    fn rss(
+        &self,
+        artist: &str,
+        post_type: PostType,
+        posts: &[(ArchivedPost, PostMetadata)],
+    ) -> String {
+        let rfc2822 = |s: &str| {
+            DateTime::parse_from_rfc3339(s)
+                .map(|d| d.to_rfc2822())
+                .unwrap_or_default()
+        };
+        let mut out = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <rss version=\"2.0\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n\
+             <channel>\n  \
+             <title>{0} {1}</title>\n  \
+             <link>https://weverse.io/{0}</link>\n  \
+             <description>Weverse {1} posts by {0}</description>\n",
+            escape_xml(artist),
+            post_type.name()
+        );
+        if let Some((_, m)) = posts.first() {
+            out += &format!(
+                "  <lastBuildDate>{}</lastBuildDate>\n",
+                rfc2822(&m.post.created_at)
+            );
+        }
+        for (archived, metadata) in posts {
+            let post = &metadata.post;
+            let url = escape_xml(&get_url(post));
+            let media: Vec<(String, &MediaFile)> = metadata
+                .files
+                .iter()
+                .filter_map(|file| Some((self.media_url(archived, file)?, file)))
+                .collect();
+            // items hold a single enclosure, further media are linked from
+            // the html description
+            let mut description = escape_xml(post.body.as_deref().unwrap_or_default());
+            for (href, file) in media.iter().skip(1) {
+                description += &format!(
+                    "<p><a href=\"{}\">{}</a></p>",
+                    escape_xml(href),
+                    escape_xml(&file.name)
+                );
+            }
+            out += &format!(
+                "  <item>\n    \
+                 <guid isPermaLink=\"true\">{0}</guid>\n    \
+                 <title>{1}</title>\n    \
+                 <link>{0}</link>\n    \
+                 <dc:creator>{2}</dc:creator>\n    \
+                 <pubDate>{3}</pubDate>\n    \
+                 <description>{4}</description>\n",
+                url,
+                escape_xml(&title(metadata)),
+                escape_xml(&post.community_user.nickname),
+                rfc2822(&post.created_at),
+                escape_xml(&description)
+            );
+            if let Some((href, file)) = media.first() {
+                out += &format!(
+                    "    <enclosure url=\"{}\" type=\"{}\" length=\"{}\"/>\n",
+                    escape_xml(href),
+                    mime_type(&file.name),
+                    file.size
+                );
+            }
+            out += "  </item>\n";
+        }
+        out += "</channel>\n</rss>\n";
+        out
+    }
+}
+
+// newest archived posts of an artist and post type
+fn recent_posts(
+    download_dir: &str,
+    artist: &str,
+    post_type: PostType,
+    max_entries: usize,
+) -> Vec<(ArchivedPost, PostMetadata)> {
+    let mut posts: Vec<(ArchivedPost, PostMetadata)> = archive::post_dirs(download_dir)
+        .into_iter()
+        .filter_map(|post| {
+            let metadata = post.read_metadata().ok().flatten()?;
+            let matches = metadata.post.community.name.eq_ignore_ascii_case(artist)
+                && metadata.post.kind().ok() == Some(post_type);
+            if matches {
+                Some((post, metadata))
+            } else {
+                None
+            }
+        })
+        .collect();
+    posts.sort_by(|(_, a), (_, b)| b.post.created_at.cmp(&a.post.created_at));
+    posts.truncate(max_entries);
+    posts
+}
+
+/// Write a feed of recent archived posts for every artist and post type, or
+/// only those in `changed` (lowercase artist name and post type)
+pub fn write_feeds(
+    conf: &Config,
+    changed: Option<&HashSet<(String, PostType)>>,
+) -> Result<(), String> {
+    let default_config = FeedsConfig::default();
+    let config = conf.feeds.as_ref().unwrap_or(&default_config);
+    let dir = PathBuf::from(config.path.as_deref().unwrap_or(DEFAULT_FEEDS_PATH));
+    fs::create_dir_all(&dir).map_err(|e| format!("Error creating {}: {}", dir.display(), e))?;
+    let feed = Feed {
+        config,
+        dir: dir.clone(),
+        cwd: PathBuf::from("."),
+    };
+
+    let mut written = 0usize;
+    for (artist, artist_config) in &conf.artists {
+        for &post_type in &[PostType::Artist, PostType::Moment] {
+            let key = (artist.to_lowercase(), post_type);
+            if changed.is_some_and(|c| !c.contains(&key)) {
+                continue;
+            }
+            let download_dir = get_download_dir(artist_config, &post_type);
+            let posts = recent_posts(
+                &download_dir,
+                artist,
+                post_type,
+                config.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES),
+            );
+            if posts.is_empty() {
+                continue;
+            }
+            let (content, ext) = match config.format {
+                FeedFormat::Atom => (feed.atom(artist, post_type, &posts), "atom"),
+                FeedFormat::Rss => (feed.rss(artist, post_type, &posts), "rss"),
+            };
+
+            // leave unchanged feeds alone so their modification time stays
+            // meaningful to static file servers
+            let path = dir.join(format!("{}-{}.{}", artist, post_type.name(), ext));
+            if fs::read_to_string(&path).is_ok_and(|old| old == content) {
+                continue;
+            }
+            fs::write(&path, content).map_err(|e| format!("{}: {}", path.display(), e))?;
+            written += 1;
+        }
+    }
+    println!("Updated {} feeds in {}", written, dir.display());
+    Ok(())
+}
@@ -0,0 +1,422 @@
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Mp4,
+    Ts,
+}
+
+const TS_PACKET_LEN: u64 = 188;
+
+// number of leading bytes needed to identify a format, enough to see the
+// sync bytes of three MPEG-TS packets
+pub const SNIFF_LEN: usize = TS_PACKET_LEN as usize * 2 + 1;
+
+// ftyp major brands of HEIF and AVIF images, which share the mp4 container
+const IMAGE_BRANDS: &[&[u8]] = &[
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+// number of leading bytes that almost always contain image dimensions
+pub const HEADER_LEN: u64 = 131072;
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => ".jpg",
+            Format::Png => ".png",
+            Format::Gif => ".gif",
+            Format::Webp => ".webp",
+            Format::Mp4 => ".mp4",
+            Format::Ts => ".ts",
+        }
+    }
+}
+
+pub fn sha256_file(path: impl AsRef<Path>) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Identify a media format from the first bytes of a file
+pub fn sniff(head: &[u8]) -> Option<Format> {
+    if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(Format::Jpeg)
+    } else if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(Format::Png)
+    } else if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+        Some(Format::Gif)
+    } else if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        Some(Format::Webp)
+    } else if head.len() >= 12 && &head[4..8] == b"ftyp" && !is_heif(head) {
+        Some(Format::Mp4)
+    } else if head.len() >= SNIFF_LEN && (0..3).all(|i| head[i * TS_PACKET_LEN as usize] == 0x47) {
+        Some(Format::Ts)
+    } else {
+        None
+    }
+}
+
+// HEIF and AVIF images, left to their Content-Type
+fn is_heif(head: &[u8]) -> bool {
+    head.len() >= 12 && &head[4..8] == b"ftyp" && IMAGE_BRANDS.contains(&&head[8..12])
+}
+
+/// Extension (with leading dot) for a downloaded file, from its first bytes,
+/// then its Content-Type, then the last path segment of its url
+pub fn extension(head: &[u8], content_type: Option<&str>, url: &str) -> String {
+    if let Some(format) = sniff(head) {
+        return format.extension().to_string();
+    }
+
+    let mime = content_type
+        .and_then(|c| c.split(';').next())
+        .map(|c| c.trim().to_lowercase());
+    let from_mime = match mime.as_deref() {
+        Some("image/jpeg") | Some("image/jpg") => Some(".jpg"),
+        Some("image/png") => Some(".png"),
+        Some("image/gif") => Some(".gif"),
+        Some("image/webp") => Some(".webp"),
+        Some("image/heic") => Some(".heic"),
+        Some("image/heif") => Some(".heif"),
+        Some("image/avif") => Some(".avif"),
+        Some("video/mp4") => Some(".mp4"),
+        Some("video/mp2t") => Some(".ts"),
+        Some("video/quicktime") => Some(".mov"),
+        _ => None,
+    };
+    if let Some(ext) = from_mime {
+        return ext.to_string();
+    }
+
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| {
+            let segment = u.path_segments()?.next_back()?.to_string();
+            let ext = &segment[segment.rfind('.')? + 1..];
+            let valid =
+                !ext.is_empty() && ext.len() <= 5 && ext.chars().all(|c| c.is_ascii_alphanumeric());
+            if valid {
+                Some(format!(".{}", ext.to_lowercase()))
+            } else {
+                None
+            }
+        })
+        .unwrap_or_default()
+}
+
+/// Width and height of an image from its first bytes
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| Some(u16::from_be_bytes([*data.get(i)?, *data.get(i + 1)?]) as u32);
+    let le16 = |i: usize| Some(u16::from_le_bytes([*data.get(i)?, *data.get(i + 1)?]) as u32);
+    let le24 = |i: usize| {
+        Some(u32::from_le_bytes([
+            *data.get(i)?,
+            *data.get(i + 1)?,
+            *data.get(i + 2)?,
+            0,
+        ]))
+    };
+    let be32 = |i: usize| {
+        let b = data.get(i..i + 4)?;
+        Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    match sniff(data)? {
+        Format::Png => Some((be32(16)?, be32(20)?)),
+        Format::Gif => Some((le16(6)?, le16(8)?)),
+        Format::Webp => match data.get(12..16)? {
+            b"VP8 " => Some((le16(26)? & 0x3fff, le16(28)? & 0x3fff)),
+            b"VP8L" => {
+                let bits = le24(21)? | (*data.get(24)? as u32) << 24;
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            }
+            b"VP8X" => Some((le24(24)? + 1, le24(27)? + 1)),
+            _ => None,
+        },
+        Format::Jpeg => {
+            // walk the segments until a start of frame marker
+            let mut i = 2;
+            loop {
+                while *data.get(i)? == 0xFF && *data.get(i + 1)? == 0xFF {
+                    i += 1;
+                }
+                if *data.get(i)? != 0xFF {
+                    return None;
+                }
+                let marker = *data.get(i + 1)?;
+                match marker {
+                    0xD0..=0xD9 | 0x01 => i += 2,
+                    0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                        return Some((be16(i + 7)?, be16(i + 5)?));
+                    }
+                    _ => i += 2 + be16(i + 2)? as usize,
+                }
+            }
+        }
+        Format::Mp4 | Format::Ts => None,
+    }
+}
+
+/// Check that a file has a recognized header and is not truncated
+pub fn validate(path: impl AsRef<Path>) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let len = file.metadata().map_err(|e| e.to_string())?.len();
+    if len == 0 {
+        return Err(String::from("empty file"));
+    }
+
+    let mut head = [0u8; SNIFF_LEN];
+    let n = read_at(&mut file, 0, &mut head)?;
+    if is_heif(&head[..n]) {
+        // same box structure as mp4
+        return if mp4_boxes_complete(&mut file, len)? {
+            Ok(())
+        } else {
+            Err(String::from("truncated HEIF file"))
+        };
+    }
+    let format = sniff(&head[..n]).ok_or_else(|| String::from("unrecognized file format"))?;
+
+    let complete = match format {
+        Format::Jpeg => {
+            // allow padding after the end of image marker
+            let tail = read_tail(&mut file, len, 1024)?;
+            let end = tail.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+            tail[..end].ends_with(&[0xFF, 0xD9])
+        }
+        Format::Png => {
+            let tail = read_tail(&mut file, len, 12)?;
+            tail == b"\x00\x00\x00\x00IEND\xAE\x42\x60\x82"
+        }
+        Format::Gif => read_tail(&mut file, len, 1)? == [0x3B],
+        Format::Webp => {
+            let riff_len = u32::from_le_bytes([head[4], head[5], head[6], head[7]]) as u64;
+            len >= riff_len + 8
+        }
+        Format::Mp4 => mp4_boxes_complete(&mut file, len)?,
+        Format::Ts => len % TS_PACKET_LEN == 0,
+    };
+
+    if complete {
+        Ok(())
+    } else {
+        Err(format!("truncated {:?} file", format))
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> Result<usize, String> {
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|e| e.to_string())?;
+    let mut total = 0;
+    while total < buf.len() {
+        match file.read(&mut buf[total..]).map_err(|e| e.to_string())? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+fn read_tail(file: &mut File, len: u64, n: u64) -> Result<Vec<u8>, String> {
+    let n = std::cmp::min(n, len);
+    let mut buf = vec![0u8; n as usize];
+    read_at(file, len - n, &mut buf)?;
+    Ok(buf)
+}
+
+// walk the top level boxes and make sure they exactly cover the file
+fn mp4_boxes_complete(file: &mut File, len: u64) -> Result<bool, String> {
+    let mut offset = 0u64;
+    while offset < len {
+        let mut header = [0u8; 16];
+        if read_at(file, offset, &mut header)? < 8 {
+            return Ok(false);
+        }
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let size = match size {
+            0 => len - offset,
+            1 => {
+                let mut large = [0u8; 8];
+                large.copy_from_slice(&header[8..16]);
+                u64::from_be_bytes(large)
+            }
+            s => s,
+        };
+        if size < 8 {
+            return Ok(false);
+        }
+        // a corrupt largesize can point past any real file
+        offset = match offset.checked_add(size) {
+            Some(o) => o,
+            None => return Ok(false),
+        };
+    }
+    Ok(offset == len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(packets: usize) -> Vec<u8> {
+        let mut data = vec![0u8; packets * TS_PACKET_LEN as usize];
+        for packet in data.chunks_mut(TS_PACKET_LEN as usize) {
+            packet[0] = 0x47;
+        }
+        data
+    }
+
+    fn webp(chunk: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut data = b"RIFF\0\0\0\0WEBP".to_vec();
+        data.extend_from_slice(chunk);
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn sniff_formats() {
+        assert_eq!(sniff(b"\xFF\xD8\xFF\xE0"), Some(Format::Jpeg));
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0"), Some(Format::Png));
+        assert_eq!(sniff(b"GIF89a\x01\0"), Some(Format::Gif));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some(Format::Webp));
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), Some(Format::Mp4));
+        assert_eq!(sniff(&ts(3)), Some(Format::Ts));
+        assert_eq!(sniff(b"<html>"), None);
+        assert_eq!(sniff(&[]), None);
+    }
+
+    #[test]
+    fn sniff_leaves_heif_and_avif_images_alone() {
+        assert_eq!(sniff(b"\0\0\0\x18ftypheic"), None);
+        assert_eq!(sniff(b"\0\0\0\x1cftypmif1"), None);
+        assert_eq!(sniff(b"\0\0\0\x20ftypavif"), None);
+        assert_eq!(sniff(b"\0\0\0\x20ftypisom"), Some(Format::Mp4));
+        // too short to see the brand
+        assert_eq!(sniff(b"\0\0\0\x18ftyp"), None);
+    }
+
+    #[test]
+    fn validate_heif() {
+        let path = std::env::temp_dir().join(format!("media-test-{}.heic", std::process::id()));
+        let mut data = b"\0\0\0\x10ftypheic\0\0\0\0".to_vec();
+        data.extend_from_slice(b"\0\0\0\x0cmdat\0\0\0\0");
+        std::fs::write(&path, &data).unwrap();
+        let complete = validate(&path);
+        std::fs::write(&path, &data[..data.len() - 2]).unwrap();
+        let truncated = validate(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(complete, Ok(()));
+        assert!(truncated.is_err());
+    }
+
+    #[test]
+    fn extensions() {
+        let url = "https://phinf.wevpstatic.net/a/b/photo.JPEG?type=w1";
+        assert_eq!(
+            extension(b"\xFF\xD8\xFF\xE0", Some("image/png"), url),
+            ".jpg"
+        );
+        assert_eq!(extension(b"\0\0\0\x18ftypmp42", None, url), ".mp4");
+        assert_eq!(
+            extension(b"\0\0\0\x18ftypheic", Some("image/heic"), url),
+            ".heic"
+        );
+        assert_eq!(
+            extension(b"\0\0\0\x20ftypavif", Some("image/avif; q=1"), url),
+            ".avif"
+        );
+        assert_eq!(extension(b"<html>", Some("Video/MP4"), url), ".mp4");
+        assert_eq!(extension(b"", None, url), ".jpeg");
+        assert_eq!(extension(b"", None, "https://example.com/a/b"), "");
+        assert_eq!(extension(b"", None, "https://example.com/a.tar-gz"), "");
+        assert_eq!(extension(b"", None, "not a url"), "");
+    }
+
+    #[test]
+    fn sniff_ts_needs_three_sync_bytes() {
+        // text that happens to start with the sync byte
+        let mut text = b"GET / HTTP/1.1".to_vec();
+        text.resize(SNIFF_LEN, b' ');
+        assert_eq!(sniff(&text), None);
+
+        let mut data = ts(3);
+        data[2 * TS_PACKET_LEN as usize] = 0;
+        assert_eq!(sniff(&data), None);
+        assert_eq!(sniff(&ts(3)[..SNIFF_LEN - 1]), None);
+    }
+
+    #[test]
+    fn dimensions_png_gif() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&640u32.to_be_bytes());
+        png.extend_from_slice(&480u32.to_be_bytes());
+        assert_eq!(dimensions(&png), Some((640, 480)));
+
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&300u16.to_le_bytes());
+        gif.extend_from_slice(&200u16.to_le_bytes());
+        assert_eq!(dimensions(&gif), Some((300, 200)));
+    }
+
+    #[test]
+    fn dimensions_jpeg() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        // APP0 segment to skip before the frame header
+        jpeg.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x10]);
+        jpeg.extend_from_slice(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        jpeg.extend_from_slice(&[0xFF, 0xC0, 0x00, 0x11, 0x08]);
+        jpeg.extend_from_slice(&1080u16.to_be_bytes());
+        jpeg.extend_from_slice(&1920u16.to_be_bytes());
+        assert_eq!(dimensions(&jpeg), Some((1920, 1080)));
+
+        // cut off before any frame header
+        assert_eq!(dimensions(&jpeg[..10]), None);
+    }
+
+    #[test]
+    fn dimensions_webp() {
+        let mut vp8 = vec![0, 0, 0, 0x9d, 0x01, 0x2a];
+        vp8.extend_from_slice(&800u16.to_le_bytes());
+        vp8.extend_from_slice(&600u16.to_le_bytes());
+        assert_eq!(dimensions(&webp(b"VP8 ", &vp8)), Some((800, 600)));
+
+        let bits: u32 = (100 - 1) | (50 - 1) << 14;
+        let mut vp8l = vec![0x2f];
+        vp8l.extend_from_slice(&bits.to_le_bytes());
+        assert_eq!(dimensions(&webp(b"VP8L", &vp8l)), Some((100, 50)));
+
+        let mut vp8x = vec![0u8; 4];
+        vp8x.extend_from_slice(&(4000u32 - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(3000u32 - 1).to_le_bytes()[..3]);
+        assert_eq!(dimensions(&webp(b"VP8X", &vp8x)), Some((4000, 3000)));
+    }
+
+    #[test]
+    fn validate_mp4_with_huge_box() {
+        let path = std::env::temp_dir().join(format!("media-test-{}.mp4", std::process::id()));
+        let mut data = b"\0\0\0\x10ftypmp42\0\0\0\0".to_vec();
+        // box with a largesize that overflows the offset
+        data.extend_from_slice(b"\0\0\0\x01mdat");
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        std::fs::write(&path, &data).unwrap();
+        let result = validate(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn dimensions_video() {
+        assert_eq!(dimensions(&ts(3)), None);
+    }
+}
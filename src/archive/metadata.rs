@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::config::ContentKind;
+use crate::network::network_structs::Post;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PostMetadata {
+    pub post: Post,
+    #[serde(default = "ContentKind::all")]
+    pub saved: Vec<ContentKind>,
+    pub files: Vec<MediaFile>,
+    pub archived_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_upstream_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MediaFile {
+    pub kind: FileKind,
+    pub index: usize,
+    pub name: String,
+    pub url: String,
+    // url actually downloaded, if a better variant than url was found
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    pub size: u64,
+    pub sha256: String,
+    // path of the file in the content-addressed store it is linked to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored: Option<String>,
+    // archived copy this file was removed in favor of, by dupes --keep-best
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Photo,
+    Video,
+    Thumbnail,
+}
+
+impl FileKind {
+    pub fn content_kind(self) -> ContentKind {
+        match self {
+            FileKind::Photo => ContentKind::Photos,
+            FileKind::Video | FileKind::Thumbnail => ContentKind::Videos,
+        }
+    }
+}
+
+/// File name without extension for a post's photo, video or video thumbnail
+pub fn file_stem(prefix: &str, kind: FileKind, index: usize) -> String {
+    match kind {
+        FileKind::Photo => format!("{}-img{:02}", prefix, index),
+        FileKind::Video => format!("{}-vid{:02}", prefix, index),
+        FileKind::Thumbnail => format!("{}-vid{:02}-thumb", prefix, index),
+    }
+}
+
+impl PostMetadata {
+    pub fn saves(&self, kind: ContentKind) -> bool {
+        self.saved.contains(&kind)
+    }
+
+    pub fn file(&self, kind: FileKind, index: usize) -> Option<&MediaFile> {
+        self.files
+            .iter()
+            .find(|f| f.kind == kind && f.index == index)
+    }
+}
+
+pub fn read(path: impl AsRef<Path>) -> Result<Option<PostMetadata>, String> {
+    let path = path.as_ref();
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Error reading {}: {}", path.display(), e)),
+    };
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| format!("Error parsing {}: {}", path.display(), e))
+}
+
+pub fn write(path: impl AsRef<Path>, metadata: &PostMetadata) -> Result<(), String> {
+    let path = path.as_ref();
+    let contents = serde_json::to_string_pretty(metadata)
+        .map_err(|e| format!("Error serializing metadata for {}: {}", path.display(), e))?;
+    fs::write(path, contents).map_err(|e| format!("Error writing {}: {}", path.display(), e))
+}
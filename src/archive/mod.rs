@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::archive::metadata::PostMetadata;
+use crate::config::Config;
+
+pub mod dupes;
+pub mod embed;
+pub mod export;
+pub mod feeds;
+pub mod media;
+pub mod metadata;
+pub mod reconcile;
+pub mod stats;
+pub mod store;
+pub mod thumbs;
+pub mod touch;
+pub mod verify;
+
+pub struct ArchivedPost {
+    pub id: i64,
+    pub prefix: String,
+    pub dir: PathBuf,
+    pub download_dir: PathBuf,
+}
+
+impl ArchivedPost {
+    pub fn metadata_path(&self) -> PathBuf {
+        metadata_path(&self.dir)
+    }
+
+    pub fn content_path(&self) -> PathBuf {
+        self.dir.join(format!("{}-content.txt", self.prefix))
+    }
+
+    pub fn read_metadata(&self) -> Result<Option<PostMetadata>, String> {
+        metadata::read(self.metadata_path())
+    }
+
+    /// Find a file in the post directory by name without extension
+    pub fn find_file(&self, stem: &str) -> Option<String> {
+        fs::read_dir(&self.dir)
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .find(|name| match name.strip_prefix(stem) {
+                Some(rest) => rest.is_empty() || rest.starts_with('.'),
+                None => false,
+            })
+    }
+}
+
+/// Path of the metadata file in a finished post directory
+pub fn metadata_path(dir: impl AsRef<Path>) -> PathBuf {
+    let dir = dir.as_ref();
+    let prefix = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    dir.join(format!("{}-metadata.json", prefix))
+}
+
+/// Escape text for XML and HTML content and attribute values
+pub fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Parse the post id out of a post directory name of the form
+/// `{date}-{id}-{user}`
+pub fn parse_post_id(name: &str) -> Option<i64> {
+    let mut parts = name.splitn(3, '-');
+    let date = parts.next()?;
+    if date.len() != 8 || !date.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// All distinct download directories from the config
+pub fn download_dirs(conf: &Config) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut dirs = Vec::new();
+    for artist_config in conf.artists.values() {
+        let paths = [
+            &artist_config.artist_download_path,
+            &artist_config.moments_download_path,
+        ];
+        for path in paths.iter() {
+            let dir = PathBuf::from(path.as_deref().unwrap_or("posts"));
+            if seen.insert(dir.clone()) {
+                dirs.push(dir);
+            }
+        }
+    }
+    dirs
+}
+
+/// List all finished post directories in a download directory, including
+/// those in per-member subdirectories
+pub fn post_dirs(download_dir: impl AsRef<Path>) -> Vec<ArchivedPost> {
+    let mut posts = Vec::new();
+    collect_post_dirs(download_dir.as_ref(), true, &mut posts);
+    posts.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+    posts
+}
+
+fn collect_post_dirs(download_dir: &Path, descend: bool, posts: &mut Vec<ArchivedPost>) {
+    let entries = match fs::read_dir(download_dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            continue;
+        }
+        let prefix = entry.file_name().to_string_lossy().to_string();
+        if prefix.starts_with('.') || prefix.ends_with(".temp") {
+            continue;
+        }
+        match parse_post_id(&prefix) {
+            Some(id) => posts.push(ArchivedPost {
+                id,
+                prefix,
+                dir: entry.path(),
+                download_dir: download_dir.to_path_buf(),
+            }),
+            None if descend => collect_post_dirs(&entry.path(), false, posts),
+            None => (),
+        }
+    }
+}
+
+/// List all finished post directories across every configured download directory
+pub fn all_posts(conf: &Config) -> Vec<ArchivedPost> {
+    download_dirs(conf).iter().flat_map(post_dirs).collect()
+}
+
+/// Format a byte count with binary units
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Apply f to every item on all cores, keeping results in order. Used for
+/// decoding and hashing images
+pub fn par_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let item = match items.get(i) {
+                    Some(item) => item,
+                    None => break,
+                };
+                let result = f(item);
+                results.lock().unwrap().push((i, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
@@ -0,0 +1,59 @@
+use chrono::Utc;
+use std::collections::HashSet;
+
+use crate::archive::{self, metadata};
+
+/// Mark archived posts missing from a complete crawl as deleted upstream
+pub fn reconcile(download_dir: &str, remote_ids: &HashSet<i64>) -> Result<(), String> {
+    let mut deleted = Vec::new();
+    let mut newly_deleted = 0usize;
+
+    for post in archive::post_dirs(download_dir) {
+        let metadata = match post.read_metadata() {
+            Ok(m) => m,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        let remote = remote_ids.contains(&post.id);
+
+        match metadata {
+            Some(mut m) => {
+                if !remote && m.deleted_upstream_at.is_none() {
+                    m.deleted_upstream_at = Some(Utc::now().to_rfc3339());
+                    metadata::write(post.metadata_path(), &m)?;
+                    newly_deleted += 1;
+                } else if remote && m.deleted_upstream_at.is_some() {
+                    // the post is visible again
+                    m.deleted_upstream_at = None;
+                    metadata::write(post.metadata_path(), &m)?;
+                }
+                if let Some(at) = m.deleted_upstream_at {
+                    deleted.push(format!(
+                        "{} (deleted upstream at {})",
+                        post.dir.display(),
+                        at
+                    ));
+                }
+            }
+            None if !remote => {
+                deleted.push(format!("{} (no metadata)", post.dir.display()));
+            }
+            None => (),
+        }
+    }
+
+    if !deleted.is_empty() {
+        println!(
+            "{} posts in {} are deleted upstream ({} new):",
+            deleted.len(),
+            download_dir,
+            newly_deleted
+        );
+        for line in deleted {
+            println!("  {}", line);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,346 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::archive::metadata::FileKind;
+use crate::archive::{self, human_size, ArchivedPost};
+use crate::cli::{StatsFormat, StatsOpts};
+use crate::config::Config;
+use crate::network::get_download_dir;
+use crate::network::network_structs::PostType;
+
+const UNKNOWN: &str = "unknown";
+
+#[derive(Debug, Default, Serialize)]
+struct ArtistStats {
+    posts: usize,
+    artist_posts: usize,
+    moments: usize,
+    photos: usize,
+    videos: usize,
+    media_bytes: u64,
+    disk_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Coverage {
+    artist: String,
+    post_type: &'static str,
+    remote: usize,
+    archived: usize,
+    missing: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Stats {
+    artists: BTreeMap<String, ArtistStats>,
+    // artist -> member -> YYYY-MM -> posts
+    members: BTreeMap<String, BTreeMap<String, BTreeMap<String, usize>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coverage: Option<Vec<Coverage>>,
+}
+
+// what a post is known by, from its metadata or for legacy posts from its
+// directory name, content file and which feeds save to its download directory
+struct PostInfo {
+    artist: String,
+    member: String,
+    post_type: Option<PostType>,
+    // (kind, size) of photos and videos
+    media: Vec<(FileKind, u64)>,
+}
+
+fn post_info(post: &ArchivedPost, owners: &[(String, PostType)]) -> PostInfo {
+    if let Ok(Some(metadata)) = post.read_metadata() {
+        return PostInfo {
+            artist: metadata.post.community.name.to_lowercase(),
+            member: metadata.post.community_user.nickname.clone(),
+            post_type: metadata.post.kind().ok(),
+            media: metadata
+                .files
+                .iter()
+                .filter(|f| f.kind != FileKind::Thumbnail && f.duplicate_of.is_none())
+                .map(|f| (f.kind, f.size))
+                .collect(),
+        };
+    }
+
+    // the first line of the content file is the post url
+    let artist = fs::read_to_string(post.content_path())
+        .ok()
+        .and_then(|c| {
+            let url = c.lines().next()?.to_string();
+            let artist = url.strip_prefix("https://weverse.io/")?.split('/').next()?;
+            Some(artist.to_string())
+        })
+        .or_else(|| {
+            let artists: HashSet<&String> = owners.iter().map(|(a, _)| a).collect();
+            match artists.into_iter().collect::<Vec<_>>()[..] {
+                [artist] => Some(artist.clone()),
+                _ => None,
+            }
+        })
+        .unwrap_or_else(|| String::from(UNKNOWN));
+    let types: HashSet<PostType> = owners
+        .iter()
+        .filter(|(a, _)| *a == artist)
+        .map(|(_, t)| *t)
+        .collect();
+    let post_type = match types.into_iter().collect::<Vec<_>>()[..] {
+        [post_type] => Some(post_type),
+        _ => None,
+    };
+    let media = fs::read_dir(&post.dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().to_string_lossy().to_string();
+                    let kind = if name.contains("-thumb") {
+                        return None;
+                    } else if name.contains("-img") {
+                        FileKind::Photo
+                    } else if name.contains("-vid") {
+                        FileKind::Video
+                    } else {
+                        return None;
+                    };
+                    Some((kind, e.metadata().ok()?.len()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    PostInfo {
+        artist,
+        member: post
+            .prefix
+            .splitn(3, '-')
+            .nth(2)
+            .unwrap_or(UNKNOWN)
+            .to_string(),
+        post_type,
+        media,
+    }
+}
+
+// (dev, ino) of a file, the same for every hardlink to it
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// id and size of the files in a post directory, not counting symlinks into
+// the store
+fn disk_files(post: &ArchivedPost) -> Vec<(Option<(u64, u64)>, u64)> {
+    fs::read_dir(&post.dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| fs::symlink_metadata(e.path()).ok())
+                .filter(|m| m.is_file())
+                .map(|m| (file_id(&m), m.len()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// left aligned columns padded to their widest cell
+fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:1$}", cell, width))
+            .collect();
+        padded.join("  ").trim_end().to_string() + "\n"
+    };
+    let mut out = line(headers.to_vec());
+    for row in rows {
+        out += &line(row.iter().map(String::as_str).collect());
+    }
+    out
+}
+
+fn print_table(stats: &Stats) {
+    let rows: Vec<Vec<String>> = stats
+        .artists
+        .iter()
+        .map(|(artist, s)| {
+            vec![
+                artist.clone(),
+                s.posts.to_string(),
+                s.artist_posts.to_string(),
+                s.moments.to_string(),
+                s.photos.to_string(),
+                s.videos.to_string(),
+                human_size(s.media_bytes),
+                human_size(s.disk_bytes),
+            ]
+        })
+        .collect();
+    print!(
+        "{}",
+        table(
+            &[
+                "Artist",
+                "Posts",
+                "Artist posts",
+                "Moments",
+                "Photos",
+                "Videos",
+                "Media",
+                "On disk"
+            ],
+            &rows
+        )
+    );
+
+    let mut rows = Vec::new();
+    for (artist, members) in &stats.members {
+        for (member, months) in members {
+            for (month, posts) in months {
+                rows.push(vec![
+                    artist.clone(),
+                    member.clone(),
+                    month.clone(),
+                    posts.to_string(),
+                ]);
+            }
+        }
+    }
+    println!();
+    print!("{}", table(&["Artist", "Member", "Month", "Posts"], &rows));
+
+    if let Some(coverage) = &stats.coverage {
+        let rows: Vec<Vec<String>> = coverage
+            .iter()
+            .map(|c| {
+                vec![
+                    c.artist.clone(),
+                    c.post_type.to_string(),
+                    c.remote.to_string(),
+                    c.archived.to_string(),
+                    c.missing.to_string(),
+                ]
+            })
+            .collect();
+        println!();
+        print!(
+            "{}",
+            table(&["Artist", "Type", "Remote", "Archived", "Missing"], &rows)
+        );
+    }
+}
+
+/// Summarize the archive per artist and member, and with `remote` post ids
+/// from the feeds how much of them is archived
+pub fn stats(
+    conf: &Config,
+    opts: &StatsOpts,
+    remote: Option<&HashMap<(String, PostType), HashSet<i64>>>,
+) -> Result<(), String> {
+    // feeds saving to each download directory
+    let mut owners: BTreeMap<PathBuf, Vec<(String, PostType)>> = BTreeMap::new();
+    for (artist, artist_config) in &conf.artists {
+        for &post_type in &[PostType::Artist, PostType::Moment] {
+            owners
+                .entry(PathBuf::from(get_download_dir(artist_config, &post_type)))
+                .or_default()
+                .push((artist.to_lowercase(), post_type));
+        }
+    }
+
+    let mut stats = Stats::default();
+    // files already counted for each artist, hardlinks to the same file
+    // through the store or by dupes take up space once
+    let mut counted: HashMap<String, HashSet<(u64, u64)>> = HashMap::new();
+    let mut archived_ids: HashMap<PathBuf, HashSet<i64>> = HashMap::new();
+    for (dir, owners) in &owners {
+        let posts = archive::post_dirs(dir);
+        archived_ids.insert(dir.clone(), posts.iter().map(|p| p.id).collect());
+        let infos = archive::par_map(&posts, |post| (post_info(post, owners), disk_files(post)));
+
+        for (post, (info, disk_files)) in posts.iter().zip(infos) {
+            let artist = stats.artists.entry(info.artist.clone()).or_default();
+            artist.posts += 1;
+            match info.post_type {
+                Some(PostType::Artist) => artist.artist_posts += 1,
+                Some(PostType::Moment) => artist.moments += 1,
+                None => (),
+            }
+            for (kind, size) in &info.media {
+                match kind {
+                    FileKind::Photo => artist.photos += 1,
+                    _ => artist.videos += 1,
+                }
+                artist.media_bytes += size;
+            }
+            let counted = counted.entry(info.artist.clone()).or_default();
+            for (id, size) in disk_files {
+                if id.is_none_or(|id| counted.insert(id)) {
+                    artist.disk_bytes += size;
+                }
+            }
+
+            let month = match post.prefix.get(..6) {
+                Some(date) => format!("{}-{}", &date[..4], &date[4..]),
+                None => String::from(UNKNOWN),
+            };
+            *stats
+                .members
+                .entry(info.artist)
+                .or_default()
+                .entry(info.member)
+                .or_default()
+                .entry(month)
+                .or_default() += 1;
+        }
+    }
+
+    if let Some(remote) = remote {
+        let mut coverage = Vec::new();
+        for (artist, artist_config) in &conf.artists {
+            let artist = artist.to_lowercase();
+            for &post_type in &[PostType::Artist, PostType::Moment] {
+                let dir = PathBuf::from(get_download_dir(artist_config, &post_type));
+                let empty = HashSet::new();
+                let ids = remote.get(&(artist.clone(), post_type)).unwrap_or(&empty);
+                let archived = ids
+                    .iter()
+                    .filter(|id| archived_ids.get(&dir).is_some_and(|a| a.contains(id)))
+                    .count();
+                coverage.push(Coverage {
+                    artist: artist.clone(),
+                    post_type: post_type.name(),
+                    remote: ids.len(),
+                    archived,
+                    missing: ids.len() - archived,
+                });
+            }
+        }
+        stats.coverage = Some(coverage);
+    }
+
+    match opts.format {
+        StatsFormat::Table => print_table(&stats),
+        StatsFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&stats).map_err(|e| e.to_string())?
+        ),
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+use crate::archive::media;
+use crate::config::{Config, DedupMode};
+
+// a content-addressed copy of every downloaded media file, linked into each
+// post directory that contains it
+pub struct Store {
+    dir: PathBuf,
+    mode: DedupMode,
+    // stored images carry the tags of the post that first stored them, so
+    // their size differs from the downloaded file they are keyed by
+    tagged: bool,
+}
+
+// a line of index.jsonl, one for every file linked into a post directory
+#[derive(Serialize)]
+struct IndexEntry<'a> {
+    sha256: &'a str,
+    size: u64,
+    store: &'a str,
+    path: &'a str,
+    post_id: i64,
+    duplicate: bool,
+    linked_at: String,
+}
+
+impl Store {
+    pub fn new(config: &Config) -> Option<Store> {
+        Some(Store {
+            dir: PathBuf::from(config.store_path.as_deref().unwrap_or("store")),
+            mode: config.dedup?,
+            tagged: config.embed_metadata,
+        })
+    }
+
+    fn stored_path(&self, path: &Path, sha256: &str) -> PathBuf {
+        let ext = path
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy()))
+            .unwrap_or_default();
+        self.dir
+            .join(&sha256[..2])
+            .join(format!("{}{}", sha256, ext))
+    }
+
+    /// Whether the store has an intact copy of a file with the given hash.
+    /// Sizes differing means the stored copy is damaged, unless it was tagged
+    pub fn contains(&self, path: &Path, sha256: &str) -> bool {
+        let stored = self.stored_path(path, sha256);
+        if self.tagged {
+            media::validate(&stored).is_ok()
+        } else {
+            let size = fs::metadata(path).map(|m| m.len()).ok();
+            fs::metadata(&stored).is_ok_and(|m| Some(m.len()) == size)
+        }
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join("index.jsonl")
+    }
+
+    /// Move a file into the store, or drop it if the store already has the
+    /// same content, and link it back into place. `archived_path` is where
+    /// the file ends up in the archive, recorded in the index. Returns the
+    /// path of the file in the store and whether the store already had it
+    pub fn link(
+        &self,
+        path: &Path,
+        sha256: &str,
+        archived_path: &Path,
+        post_id: i64,
+    ) -> io::Result<(PathBuf, bool)> {
+        let stored = self.stored_path(path, sha256);
+        // a damaged stored copy is replaced
+        let duplicate = self.contains(path, sha256);
+        if duplicate {
+            fs::remove_file(path)?;
+        } else {
+            fs::create_dir_all(stored.parent().unwrap())?;
+            if fs::rename(path, &stored).is_err() {
+                // the store is on another filesystem
+                fs::copy(path, &stored)?;
+                fs::remove_file(path)?;
+            }
+        }
+
+        match self.mode {
+            DedupMode::Hardlink => fs::hard_link(&stored, path)?,
+            DedupMode::Symlink => symlink(&fs::canonicalize(&stored)?, path)?,
+        }
+
+        let entry = IndexEntry {
+            sha256,
+            size: fs::metadata(&stored)?.len(),
+            store: &stored.to_string_lossy(),
+            path: &archived_path.to_string_lossy(),
+            post_id,
+            duplicate,
+            linked_at: Utc::now().to_rfc3339(),
+        };
+        let line = serde_json::to_string(&entry).map_err(io::Error::other)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.index_path())?
+            .write_all(format!("{}\n", line).as_bytes())?;
+
+        Ok((stored, duplicate))
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn png(size: u32) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        RgbImage::new(size, size)
+            .write_to(&mut data, ImageFormat::Png)
+            .unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn tagged_copies_are_found_by_downloaded_hash() {
+        let dir = std::env::temp_dir().join(format!("store-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let store = Store {
+            dir: dir.join("store"),
+            mode: DedupMode::Hardlink,
+            tagged: true,
+        };
+        let key = "ab".repeat(32);
+
+        // the first post stores its tagged copy
+        let first = dir.join("first.png");
+        fs::write(&first, png(2)).unwrap();
+        assert!(!store.contains(&first, &key));
+        let (stored, duplicate) = store.link(&first, &key, &first, 1).unwrap();
+        assert!(!duplicate);
+
+        // a repost downloads the untagged bytes, smaller than the stored copy
+        let second = dir.join("second.png");
+        fs::write(&second, png(1)).unwrap();
+        assert!(store.contains(&second, &key));
+        assert_eq!(
+            store.link(&second, &key, &second, 2).unwrap(),
+            (stored, true)
+        );
+        assert_eq!(fs::read(&second).unwrap(), png(2));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
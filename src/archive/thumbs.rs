@@ -0,0 +1,301 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::{imageops, DynamicImage, ImageReader, Rgb, RgbImage};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::archive::metadata::FileKind;
+use crate::archive::{self, media, ArchivedPost};
+use crate::config::Config;
+
+const THUMBS_DIR: &str = ".thumbs";
+const INDEX_FILE: &str = "index.json";
+const THUMB_SIZE: u32 = 320;
+const JPEG_QUALITY: u8 = 85;
+
+// contact sheets are grids of tiles, split into pages to stay well within
+// the largest JPEG dimensions
+const TILE_SIZE: u32 = 160;
+const SHEET_COLUMNS: u32 = 8;
+const SHEET_PAGE_LEN: usize = 8 * 50;
+
+// a photo or video poster frame and where its thumbnail goes
+struct Source {
+    path: PathBuf,
+    thumb: PathBuf,
+    key: String,
+    sha256: Option<String>,
+}
+
+// post directory relative to its download directory, which the .thumbs tree
+// mirrors
+fn relative_dir(post: &ArchivedPost) -> PathBuf {
+    post.dir
+        .strip_prefix(&post.download_dir)
+        .map_or_else(|_| PathBuf::from(&post.prefix), Path::to_path_buf)
+}
+
+// photos and video thumbnails of a post, using the hashes in its metadata
+// when it has any
+fn sources(post: &ArchivedPost, thumbs_dir: &Path) -> Vec<Source> {
+    let rel = relative_dir(post);
+    let files: Vec<(String, Option<String>)> = match post.read_metadata() {
+        Ok(Some(m)) => m
+            .files
+            .into_iter()
+            .filter(|f| f.kind != FileKind::Video && f.duplicate_of.is_none())
+            .map(|f| (f.name, Some(f.sha256)))
+            .collect(),
+        Ok(None) => fs::read_dir(&post.dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .filter(|name| name.contains("-img") || name.contains("-thumb"))
+                    .map(|name| (name, None))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        Err(e) => {
+            println!("{}", e);
+            Vec::new()
+        }
+    };
+
+    let mut sources: Vec<Source> = files
+        .into_iter()
+        .map(|(name, sha256)| {
+            let stem = Path::new(&name)
+                .file_stem()
+                .map_or_else(|| name.clone(), |s| s.to_string_lossy().to_string());
+            Source {
+                path: post.dir.join(&name),
+                thumb: thumbs_dir.join(&rel).join(format!("{}.jpg", stem)),
+                key: rel.join(&name).to_string_lossy().to_string(),
+                sha256,
+            }
+        })
+        .collect();
+    sources.sort_by(|a, b| a.key.cmp(&b.key));
+    sources
+}
+
+fn save_jpeg(image: &RgbImage, path: &Path) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    JpegEncoder::new_with_quality(BufWriter::new(file), JPEG_QUALITY)
+        .encode_image(image)
+        .map_err(|e| e.to_string())
+}
+
+fn open_image(path: &Path) -> Result<DynamicImage, String> {
+    ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .map_err(|e| e.to_string())?
+        .decode()
+        .map_err(|e| e.to_string())
+}
+
+fn make_thumb(source: &Path, thumb: &Path) -> Result<(), String> {
+    let image = open_image(source)?;
+    save_jpeg(&image.thumbnail(THUMB_SIZE, THUMB_SIZE).to_rgb8(), thumb)
+}
+
+// a grid of thumbnails, each scaled to fit a tile
+fn make_sheet(thumbs: &[&PathBuf], path: &Path) -> Result<(), String> {
+    let columns = SHEET_COLUMNS.min(thumbs.len() as u32).max(1);
+    let rows = (thumbs.len() as u32).div_ceil(columns);
+    let mut sheet = RgbImage::from_pixel(columns * TILE_SIZE, rows * TILE_SIZE, Rgb([24, 24, 24]));
+    for (i, thumb) in thumbs.iter().enumerate() {
+        let tile = match open_image(thumb) {
+            Ok(t) => t.thumbnail(TILE_SIZE, TILE_SIZE).to_rgb8(),
+            Err(_) => continue,
+        };
+        let x = (i as u32 % columns) * TILE_SIZE + (TILE_SIZE - tile.width()) / 2;
+        let y = (i as u32 / columns) * TILE_SIZE + (TILE_SIZE - tile.height()) / 2;
+        imageops::overlay(&mut sheet, &tile, x as i64, y as i64);
+    }
+    save_jpeg(&sheet, path)
+}
+
+// hash identifying the thumbnails a contact sheet is made of
+fn sheet_hash(members: &[&Source]) -> String {
+    let mut hasher = Sha256::new();
+    for source in members {
+        hasher.update(source.key.as_bytes());
+        hasher.update(source.sha256.as_deref().unwrap_or_default().as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+// month of a post, from the date at the start of its directory name
+fn month(post: &ArchivedPost) -> Option<String> {
+    let date = post.prefix.get(..6)?;
+    Some(format!("{}-{}", &date[..4], &date[4..]))
+}
+
+struct Counts {
+    thumbs: usize,
+    sheets: usize,
+    failed: usize,
+}
+
+fn thumbs_for_dir(download_dir: &Path, counts: &mut Counts) -> Result<(), String> {
+    let thumbs_dir = download_dir.join(THUMBS_DIR);
+    let index_path = thumbs_dir.join(INDEX_FILE);
+    let old_index: HashMap<String, String> = match fs::read_to_string(&index_path) {
+        Ok(s) => serde_json::from_str(&s)
+            .map_err(|e| format!("Error parsing {}: {}", index_path.display(), e))?,
+        Err(_) => HashMap::new(),
+    };
+    let mut index = HashMap::new();
+
+    let posts = archive::post_dirs(download_dir);
+    let mut post_sources: Vec<(&ArchivedPost, Vec<Source>)> = posts
+        .iter()
+        .map(|post| (post, sources(post, &thumbs_dir)))
+        .collect();
+
+    // hash files of posts without metadata
+    let mut all: Vec<&mut Source> = post_sources
+        .iter_mut()
+        .flat_map(|(_, sources)| sources.iter_mut())
+        .collect();
+    let hashes = archive::par_map(&all, |s| match &s.sha256 {
+        Some(h) => Some(h.clone()),
+        None => media::sha256_file(&s.path).ok(),
+    });
+    for (source, hash) in all.iter_mut().zip(hashes) {
+        source.sha256 = hash;
+    }
+
+    // regenerate thumbnails whose source changed
+    let stale: Vec<&Source> = post_sources
+        .iter()
+        .flat_map(|(_, sources)| sources.iter())
+        .filter(|s| s.sha256.is_some())
+        .filter(|s| old_index.get(&s.key) != s.sha256.as_ref() || !s.thumb.exists())
+        .collect();
+    let results = archive::par_map(&stale, |s| make_thumb(&s.path, &s.thumb));
+    let mut failed = HashSet::new();
+    for (source, result) in stale.iter().zip(results) {
+        match result {
+            Ok(()) => counts.thumbs += 1,
+            Err(e) => {
+                counts.failed += 1;
+                failed.insert(source.key.clone());
+                println!(
+                    "Error creating thumbnail for {}: {}",
+                    source.path.display(),
+                    e
+                );
+            }
+        }
+    }
+    let done = |s: &&Source| s.sha256.is_some() && !failed.contains(&s.key);
+    for source in post_sources.iter().flat_map(|(_, s)| s.iter()).filter(done) {
+        index.insert(source.key.clone(), source.sha256.clone().unwrap());
+    }
+
+    // contact sheets per post and per month
+    let mut sheets: Vec<(String, PathBuf, Vec<&Source>)> = Vec::new();
+    let mut months: BTreeMap<String, Vec<&Source>> = BTreeMap::new();
+    for (post, sources) in &post_sources {
+        let members: Vec<&Source> = sources.iter().filter(done).collect();
+        if members.is_empty() {
+            continue;
+        }
+        let rel = relative_dir(post);
+        sheets.push((
+            format!("sheet:{}", rel.display()),
+            thumbs_dir.join(&rel).join("contact-sheet.jpg"),
+            members.clone(),
+        ));
+        if let Some(month) = month(post) {
+            months.entry(month).or_default().extend(members);
+        }
+    }
+    for (month, members) in months {
+        for (page, chunk) in members.chunks(SHEET_PAGE_LEN).enumerate() {
+            let name = match page {
+                0 => format!("{}.jpg", month),
+                n => format!("{}-{}.jpg", month, n + 1),
+            };
+            sheets.push((
+                format!("month:{}", name),
+                thumbs_dir.join("months").join(name),
+                chunk.to_vec(),
+            ));
+        }
+    }
+
+    let stale: Vec<&(String, PathBuf, Vec<&Source>)> = sheets
+        .iter()
+        .filter(|(key, path, members)| {
+            old_index.get(key) != Some(&sheet_hash(members)) || !path.exists()
+        })
+        .collect();
+    let results = archive::par_map(&stale, |(_, path, members)| {
+        let thumbs: Vec<&PathBuf> = members.iter().map(|s| &s.thumb).collect();
+        make_sheet(&thumbs, path)
+    });
+    let mut failed_sheets = HashSet::new();
+    for ((key, path, _), result) in stale.iter().zip(results) {
+        match result {
+            Ok(()) => counts.sheets += 1,
+            Err(e) => {
+                counts.failed += 1;
+                failed_sheets.insert(key.clone());
+                println!("Error creating {}: {}", path.display(), e);
+            }
+        }
+    }
+    for (key, _, members) in sheets.iter().filter(|(k, _, _)| !failed_sheets.contains(k)) {
+        index.insert(key.clone(), sheet_hash(members));
+    }
+
+    // drop thumbnails of files that are gone
+    for key in old_index.keys().filter(|k| !index.contains_key(*k)) {
+        let path = match key.strip_prefix("month:") {
+            Some(name) => thumbs_dir.join("months").join(name),
+            None => match key.strip_prefix("sheet:") {
+                Some(rel) => thumbs_dir.join(rel).join("contact-sheet.jpg"),
+                None => thumbs_dir.join(Path::new(key).with_extension("jpg")),
+            },
+        };
+        let _ = fs::remove_file(&path);
+        if let Some(parent) = path.parent() {
+            // only succeeds once the directory is empty
+            let _ = fs::remove_dir(parent);
+        }
+    }
+
+    if !index.is_empty() {
+        fs::create_dir_all(&thumbs_dir).map_err(|e| e.to_string())?;
+        let json = serde_json::to_string_pretty(&index).map_err(|e| e.to_string())?;
+        fs::write(&index_path, json).map_err(|e| format!("{}: {}", index_path.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Create thumbnails and contact sheets for the whole archive in a .thumbs
+/// directory inside each download directory
+pub fn thumbs(conf: &Config) -> Result<(), String> {
+    let mut counts = Counts {
+        thumbs: 0,
+        sheets: 0,
+        failed: 0,
+    };
+    for dir in archive::download_dirs(conf) {
+        thumbs_for_dir(&dir, &mut counts)?;
+    }
+    println!(
+        "Created {} thumbnails and {} contact sheets, {} failed",
+        counts.thumbs, counts.sheets, counts.failed
+    );
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use filetime::FileTime;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::archive::{self, ArchivedPost};
+use crate::config::{Config, MtimeSource};
+use crate::network::network_structs::Post;
+
+/// The time a post's files are stamped with
+pub fn post_time(post: &Post, source: MtimeSource) -> Option<SystemTime> {
+    let time = match source {
+        MtimeSource::Created => &post.created_at,
+        MtimeSource::Updated if !post.updated_at.is_empty() => &post.updated_at,
+        MtimeSource::Updated => &post.created_at,
+    };
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(SystemTime::from)
+}
+
+// created_at from the "nickname (created_at):" line of content.txt, or the
+// date in the directory name, for posts archived without metadata
+fn legacy_time(archived: &ArchivedPost) -> Option<SystemTime> {
+    let content = fs::read_to_string(archived.content_path()).unwrap_or_default();
+    let from_content = content.lines().nth(1).and_then(|line| {
+        let time = line.strip_suffix("):")?;
+        let time = &time[time.rfind(" (")? + 2..];
+        DateTime::parse_from_rfc3339(time).ok()
+    });
+    match from_content {
+        Some(time) => Some(time.into()),
+        None => {
+            let date = NaiveDate::parse_from_str(archived.prefix.get(..8)?, "%Y%m%d").ok()?;
+            Some(Utc.from_utc_datetime(&date.and_hms(0, 0, 0)).into())
+        }
+    }
+}
+
+// hardlinked files, such as those linked from the store, are shared with
+// other posts
+#[cfg(unix)]
+fn is_shared(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn is_shared(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+/// Set the modification time of a post directory and every file in it.
+/// Symlinks are stamped themselves and files shared with other posts are left
+/// alone
+pub fn stamp(dir: impl AsRef<Path>, time: SystemTime) -> io::Result<()> {
+    let dir = dir.as_ref();
+    let time = FileTime::from_system_time(time);
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = fs::symlink_metadata(&path)?;
+        if meta.file_type().is_symlink() {
+            filetime::set_symlink_file_times(&path, FileTime::from_last_access_time(&meta), time)?;
+        } else if !is_shared(&meta) {
+            filetime::set_file_mtime(&path, time)?;
+        }
+    }
+    filetime::set_file_mtime(dir, time)
+}
+
+/// Set the modification time of a single file, such as one in the store
+pub fn stamp_file(path: impl AsRef<Path>, time: SystemTime) -> io::Result<()> {
+    filetime::set_file_mtime(path, FileTime::from_system_time(time))
+}
+
+/// Stamp every archived post with its post time
+pub fn touch(conf: &Config) -> Result<(), String> {
+    let mut touched = 0usize;
+    let mut failed = 0usize;
+    for archived in archive::all_posts(conf) {
+        let time = match archived.read_metadata() {
+            Ok(Some(m)) => post_time(&m.post, conf.mtime),
+            Ok(None) => legacy_time(&archived),
+            Err(e) => {
+                println!("{}", e);
+                None
+            }
+        };
+        let result = match time {
+            Some(time) => stamp(&archived.dir, time).map_err(|e| e.to_string()),
+            None => Err(String::from("could not find the post time")),
+        };
+        match result {
+            Ok(()) => touched += 1,
+            Err(e) => {
+                failed += 1;
+                println!("Error touching {}: {}", archived.dir.display(), e);
+            }
+        }
+    }
+    println!(
+        "Set modification times of {} posts, {} failed",
+        touched, failed
+    );
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::archive::media;
+use crate::archive::metadata::{FileKind, MediaFile, PostMetadata};
+use crate::archive::{self, ArchivedPost};
+use crate::config::{Config, ContentKind};
+
+#[derive(Debug)]
+pub enum Problem {
+    NoMetadata,
+    BadMetadata(String),
+    MissingContent,
+    MissingRecord(FileKind, usize),
+    MissingFile(String),
+    SizeMismatch(String, u64, u64),
+    HashMismatch(String),
+    InvalidFile(String, String),
+}
+
+impl Problem {
+    // legacy posts without metadata are only broken if their files are
+    pub fn is_broken(&self) -> bool {
+        !matches!(self, Problem::NoMetadata)
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::NoMetadata => write!(f, "no metadata"),
+            Problem::BadMetadata(e) => write!(f, "bad metadata: {}", e),
+            Problem::MissingContent => write!(f, "missing content.txt"),
+            Problem::MissingRecord(k, i) => write!(f, "no record for {:?} {}", k, i),
+            Problem::MissingFile(s) => write!(f, "missing {}", s),
+            Problem::SizeMismatch(s, expected, actual) => {
+                write!(f, "{} is {} bytes, expected {}", s, actual, expected)
+            }
+            Problem::HashMismatch(s) => write!(f, "{} hash mismatch", s),
+            Problem::InvalidFile(s, e) => write!(f, "{} is invalid: {}", s, e),
+        }
+    }
+}
+
+/// Photos and videos the post is expected to have on disk
+pub fn expected_files(metadata: &PostMetadata) -> Vec<(FileKind, usize)> {
+    metadata
+        .post
+        .media_urls(&metadata.saved)
+        .into_iter()
+        .map(|(kind, i, _)| (kind, i))
+        .collect()
+}
+
+pub fn check_file(dir: &Path, file: &MediaFile) -> Option<Problem> {
+    if file.duplicate_of.is_some() {
+        // removed on purpose in favor of a better copy
+        return None;
+    }
+    let path = dir.join(&file.name);
+    let len = match fs::metadata(&path) {
+        Ok(m) => m.len(),
+        Err(_) => return Some(Problem::MissingFile(file.name.clone())),
+    };
+    if len != file.size {
+        return Some(Problem::SizeMismatch(file.name.clone(), file.size, len));
+    }
+    if let Err(e) = media::validate(&path) {
+        return Some(Problem::InvalidFile(file.name.clone(), e));
+    }
+    match media::sha256_file(&path) {
+        Ok(hash) if hash == file.sha256 => None,
+        Ok(_) => Some(Problem::HashMismatch(file.name.clone())),
+        Err(e) => Some(Problem::InvalidFile(file.name.clone(), e.to_string())),
+    }
+}
+
+pub fn check_post(post: &ArchivedPost) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let has_content = fs::metadata(post.content_path()).is_ok();
+
+    let metadata = match post.read_metadata() {
+        Ok(Some(m)) => m,
+        Ok(None) => {
+            // posts archived before metadata existed, only check headers
+            if !has_content {
+                problems.push(Problem::MissingContent);
+            }
+            problems.push(Problem::NoMetadata);
+            problems.extend(check_legacy_files(post));
+            return problems;
+        }
+        Err(e) => {
+            problems.push(Problem::BadMetadata(e));
+            return problems;
+        }
+    };
+
+    if !has_content && metadata.saves(ContentKind::Text) {
+        problems.push(Problem::MissingContent);
+    }
+
+    for (kind, index) in expected_files(&metadata) {
+        match metadata.file(kind, index) {
+            Some(file) => problems.extend(check_file(&post.dir, file)),
+            None => problems.push(Problem::MissingRecord(kind, index)),
+        }
+    }
+    problems
+}
+
+fn check_legacy_files(post: &ArchivedPost) -> Vec<Problem> {
+    let entries = match fs::read_dir(&post.dir) {
+        Ok(e) => e,
+        Err(e) => return vec![Problem::MissingFile(e.to_string())],
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter(|name| name.contains("-img") || name.contains("-vid"))
+        .filter_map(|name| {
+            media::validate(post.dir.join(&name))
+                .err()
+                .map(|e| Problem::InvalidFile(name, e))
+        })
+        .collect()
+}
+
+pub fn verify(conf: &Config, quarantine: bool) -> Result<(), String> {
+    let mut checked = 0usize;
+    let mut broken = 0usize;
+    for post in archive::all_posts(conf) {
+        checked += 1;
+        let problems = check_post(&post);
+        if !problems.iter().any(Problem::is_broken) {
+            continue;
+        }
+        broken += 1;
+
+        let problems = problems
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!("{}: {}", post.dir.display(), problems);
+
+        if quarantine {
+            let quarantine_dir = post.download_dir.join(".quarantine");
+            fs::create_dir_all(&quarantine_dir)
+                .map_err(|e| format!("Error creating {}: {}", quarantine_dir.display(), e))?;
+            let dest = quarantine_dir.join(&post.prefix);
+            fs::rename(&post.dir, &dest)
+                .map_err(|e| format!("Error moving {}: {}", post.dir.display(), e))?;
+            println!("Moved {} to {}", post.dir.display(), dest.display());
+        }
+    }
+
+    println!("Checked {} posts, {} broken", checked, broken);
+    if quarantine && broken > 0 {
+        // quarantined posts are usually older than the point where a normal
+        // sync stops paging
+        println!("Run sync --full to download the quarantined posts again");
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use chrono::NaiveDate;
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+use crate::config::{parse_date, ContentKind};
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Batch download Weverse posts and moments")]
+pub struct Opt {
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct SyncOpts {
+    /// Page through every post instead of stopping at already archived posts
+    #[structopt(long)]
+    pub full: bool,
+    /// Only download posts created on or after this date (YYYY-MM-DD)
+    #[structopt(long, parse(try_from_str = parse_date))]
+    pub since: Option<NaiveDate>,
+    /// Only download posts created on or before this date (YYYY-MM-DD)
+    #[structopt(long, parse(try_from_str = parse_date))]
+    pub until: Option<NaiveDate>,
+    /// What to save from each post: any of photos, videos, text (comma separated)
+    #[structopt(long, use_delimiter = true)]
+    pub save: Option<Vec<ContentKind>>,
+    /// Only download posts containing any of photos, videos, text (text meaning text only)
+    #[structopt(long, use_delimiter = true)]
+    pub keep_posts_with: Option<Vec<ContentKind>>,
+    /// Show what would be downloaded and roughly how much, without writing anything
+    #[structopt(long)]
+    pub dry_run: bool,
+}
+
+impl SyncOpts {
+    /// Reject a date range that can't contain any post
+    pub fn check(&self) -> Result<(), String> {
+        match (self.since, self.until) {
+            (Some(since), Some(until)) if since > until => Err(format!(
+                "Error: --since {} is after --until {}",
+                since, until
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReportFormat {
+    Text,
+    Json,
+    Html,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(format!(
+                "Error parsing format {} (expected text, json or html)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DupesOpts {
+    /// Most bits (of 64) that may differ in each perceptual hash of two near-duplicates
+    #[structopt(long, default_value = "10")]
+    pub threshold: u32,
+    /// Report format: text, json or html
+    #[structopt(long, default_value = "text")]
+    pub format: ReportFormat,
+    /// Write the report to this file instead of standard output
+    #[structopt(long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+    /// Remove all but the highest resolution copy in each cluster
+    #[structopt(long)]
+    pub keep_best: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    Csv,
+    Sqlite,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "sqlite" => Ok(ExportFormat::Sqlite),
+            _ => Err(format!(
+                "Error parsing format {} (expected csv or sqlite)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExportOpts {
+    /// Export format: csv or sqlite
+    #[structopt(long, default_value = "csv")]
+    pub format: ExportFormat,
+    /// File to write, standard output for csv and weverse.sqlite for sqlite by default
+    #[structopt(long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+    /// Replace the sqlite output even if it is not an earlier export
+    #[structopt(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StatsFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "table" => Ok(StatsFormat::Table),
+            "json" => Ok(StatsFormat::Json),
+            _ => Err(format!(
+                "Error parsing format {} (expected table or json)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StatsOpts {
+    /// Output format: table or json
+    #[structopt(long, default_value = "table")]
+    pub format: StatsFormat,
+    /// Crawl every feed to report how many remote posts are archived
+    #[structopt(long)]
+    pub remote: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct GetOpts {
+    /// Post urls (https://weverse.io/{artist}/artist/{post_id}) or ids
+    pub posts: Vec<String>,
+    /// Artist of the posts given by id
+    #[structopt(long)]
+    pub artist: Option<String>,
+    /// Also read post urls or ids from this file, one per line, or from standard input with -
+    #[structopt(long, parse(from_os_str))]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Download new posts for all configured artists (default)
+    Sync(SyncOpts),
+    /// Check archived posts for missing, truncated or corrupt media
+    Verify {
+        /// Move broken posts into a .quarantine directory so they are downloaded again
+        #[structopt(long)]
+        quarantine: bool,
+    },
+    /// Download missing or corrupt media for broken posts found by verify
+    Repair,
+    /// Set modification times of archived posts and their files to the post time
+    Touch,
+    /// Find near-duplicate photos, such as re-encoded or resized copies, across the archive
+    Dupes(DupesOpts),
+    /// Create thumbnails and per post and per month contact sheets in a .thumbs directory
+    Thumbs,
+    /// Write Atom or RSS feeds of recent archived posts for every artist and post type
+    Feeds,
+    /// Send a test notification to every configured webhook
+    TestWebhooks,
+    /// Export the metadata of every archived post as CSV or SQLite
+    Export(ExportOpts),
+    /// Summarize posting activity, media and coverage of the archive
+    Stats(StatsOpts),
+    /// Download specific posts by url or id
+    Get(GetOpts),
+}
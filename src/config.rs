@@ -1,26 +1,224 @@
+use chrono::{DateTime, NaiveDate};
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
+use std::str::FromStr;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub cookies_file: String,
+    #[allow(dead_code)]
     #[serde(default = "default_keep_open")]
     pub keep_open: bool,
     #[serde(default = "default_num_processes")]
     pub max_connections: usize,
+    pub max_api_connections: Option<usize>,
+    pub max_media_connections: Option<usize>,
+    pub max_connections_per_host: Option<usize>,
+    pub bandwidth_limit: Option<u64>,
+    #[serde(default = "default_download_queue_size")]
+    pub download_queue_size: usize,
+    #[serde(default = "default_stop_after_archived")]
+    pub stop_after_archived: usize,
+    pub hls_max_bandwidth: Option<u64>,
+    #[serde(default)]
+    pub embed_metadata: bool,
+    #[serde(default)]
+    pub mtime: MtimeSource,
+    pub dedup: Option<DedupMode>,
+    pub store_path: Option<String>,
+    pub feeds: Option<FeedsConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    pub post_download_hook: Option<String>,
     pub artists: HashMap<String, ArtistConfig>,
 }
 
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct FeedsConfig {
+    pub path: Option<String>,
+    #[serde(default)]
+    pub format: FeedFormat,
+    pub base_url: Option<String>,
+    pub max_entries: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    pub artists: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    #[default]
+    Json,
+    Discord,
+    Slack,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedFormat {
+    #[default]
+    Atom,
+    Rss,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ArtistConfig {
     pub artist_download_path: Option<String>,
     pub moments_download_path: Option<String>,
+    #[allow(dead_code)]
     pub videos_download_path: Option<String>,
     pub recent_artist: Option<isize>,
     pub recent_moments: Option<isize>,
+    #[allow(dead_code)]
     pub recent_videos: Option<isize>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub members: Option<Vec<String>>,
+    pub exclude_members: Option<Vec<String>>,
+    #[serde(default)]
+    pub member_subdirs: bool,
+    pub save: Option<Vec<ContentKind>>,
+    pub keep_posts_with: Option<Vec<ContentKind>>,
+    pub post_download_hook: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    Photos,
+    Videos,
+    Text,
+}
+
+// how files in the content-addressed store are linked into post directories
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupMode {
+    Hardlink,
+    Symlink,
+}
+
+// which post time files are stamped with
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MtimeSource {
+    #[default]
+    Created,
+    Updated,
+}
+
+impl ContentKind {
+    pub fn all() -> Vec<ContentKind> {
+        vec![ContentKind::Photos, ContentKind::Videos, ContentKind::Text]
+    }
+}
+
+impl FromStr for ContentKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "photos" => Ok(ContentKind::Photos),
+            "videos" => Ok(ContentKind::Videos),
+            "text" => Ok(ContentKind::Text),
+            "comments" => Err(String::from(
+                "Error: comments can't be saved (expected photos, videos or text)",
+            )),
+            _ => Err(format!(
+                "Error parsing content kind {} (expected photos, videos or text)",
+                s
+            )),
+        }
+    }
+}
+
+// inclusive range of post creation dates to download
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DateRange {
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+}
+
+impl DateRange {
+    pub fn is_after(&self, date: NaiveDate) -> bool {
+        matches!(self.until, Some(until) if date > until)
+    }
+
+    pub fn is_before(&self, date: NaiveDate) -> bool {
+        matches!(self.since, Some(since) if date < since)
+    }
+}
+
+impl ArtistConfig {
+    // what to save from each downloaded post
+    pub fn saved_content(&self) -> Vec<ContentKind> {
+        self.save.clone().unwrap_or_else(ContentKind::all)
+    }
+
+    // whether posts by this member should be downloaded
+    pub fn includes_member(&self, nickname: &str) -> bool {
+        let matches = |names: &Vec<String>| names.iter().any(|n| n.eq_ignore_ascii_case(nickname));
+        self.members.as_ref().is_none_or(matches)
+            && !self.exclude_members.as_ref().is_some_and(matches)
+    }
+
+    // date range for this artist, with command line bounds taking precedence
+    pub fn date_range(
+        &self,
+        since: Option<NaiveDate>,
+        until: Option<NaiveDate>,
+    ) -> Result<DateRange, String> {
+        let since = match (since, &self.since) {
+            (Some(d), _) => Some(d),
+            (None, Some(s)) => Some(parse_date(s)?),
+            (None, None) => None,
+        };
+        let until = match (until, &self.until) {
+            (Some(d), _) => Some(d),
+            (None, Some(s)) => Some(parse_date(s)?),
+            (None, None) => None,
+        };
+        match (since, until) {
+            (Some(since), Some(until)) if since > until => {
+                Err(format!("Error: since {} is after until {}", since, until))
+            }
+            _ => Ok(DateRange { since, until }),
+        }
+    }
+}
+
+pub fn parse_date(s: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|e| format!("Error parsing date {} (expected YYYY-MM-DD): {}", s, e))
+}
+
+// date a post was created, in the timezone it was posted from
+pub fn post_date(created_at: &str) -> Option<NaiveDate> {
+    DateTime::parse_from_rfc3339(created_at)
+        .ok()
+        .map(|d| d.naive_local().date())
+}
+
+impl Config {
+    pub fn api_connections(&self) -> usize {
+        self.max_api_connections
+            .unwrap_or(self.max_connections)
+            .max(1)
+    }
+
+    pub fn media_connections(&self) -> usize {
+        self.max_media_connections
+            .unwrap_or(self.max_connections)
+            .max(1)
+    }
 }
 
 fn default_keep_open() -> bool {
@@ -29,6 +227,12 @@ fn default_keep_open() -> bool {
 fn default_num_processes() -> usize {
     20
 }
+fn default_download_queue_size() -> usize {
+    100
+}
+fn default_stop_after_archived() -> usize {
+    20
+}
 
 pub fn read_config() -> Result<Config, String> {
     let conf_contents = fs::read_to_string("config.toml")
@@ -46,7 +250,7 @@ pub fn read_token(cookies_file: &str) -> Result<String, String> {
         ).unwrap();
     }
 
-    let cookies_contents = fs::read_to_string(&cookies_file)
+    let cookies_contents = fs::read_to_string(cookies_file)
         .map_err(|e| format!("Error reading {}: {}", &cookies_file, e))?;
 
     let token = RE
@@ -60,3 +264,34 @@ pub fn read_token(cookies_file: &str) -> Result<String, String> {
     // println!("token: {:?}", token);
     Ok(token)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artist(toml: &str) -> ArtistConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn date(s: &str) -> Option<NaiveDate> {
+        Some(parse_date(s).unwrap())
+    }
+
+    #[test]
+    fn date_range_prefers_command_line() {
+        let a = artist("since = \"2020-01-01\"\nuntil = \"2020-12-31\"");
+        let range = a.date_range(date("2020-06-01"), None).unwrap();
+        assert_eq!(range.since, date("2020-06-01"));
+        assert_eq!(range.until, date("2020-12-31"));
+    }
+
+    #[test]
+    fn date_range_rejects_since_after_until() {
+        let a = artist("since = \"2021-01-01\"\nuntil = \"2020-12-31\"");
+        assert!(a.date_range(None, None).is_err());
+        // bounds from the command line and the config together
+        let a = artist("until = \"2020-12-31\"");
+        assert!(a.date_range(date("2021-01-01"), None).is_err());
+        assert!(a.date_range(date("2020-12-31"), None).is_ok());
+    }
+}
@@ -2,10 +2,15 @@
 extern crate lazy_static;
 
 use std::process;
+use structopt::StructOpt;
 
+mod archive;
+mod cli;
 mod config;
 mod network;
 
+use cli::{Command, Opt};
+
 #[tokio::main]
 async fn main() {
     match run().await {
@@ -18,7 +23,42 @@ async fn main() {
 }
 
 async fn run() -> Result<(), String> {
+    let opt = Opt::from_args();
+    if let Some(Command::Sync(sync_opts)) = &opt.command {
+        sync_opts.check()?;
+    }
     let conf = config::read_config()?;
-    let token = config::read_token(&conf.cookies_file)?;
-    network::download(&conf, &token).await
+    match opt
+        .command
+        .unwrap_or_else(|| Command::Sync(Default::default()))
+    {
+        Command::Sync(sync_opts) => {
+            let token = config::read_token(&conf.cookies_file)?;
+            network::download(&conf, &token, &sync_opts).await
+        }
+        Command::Verify { quarantine } => archive::verify::verify(&conf, quarantine),
+        Command::Repair => {
+            let token = config::read_token(&conf.cookies_file)?;
+            network::repair(&conf, &token).await
+        }
+        Command::Touch => archive::touch::touch(&conf),
+        Command::Dupes(dupes_opts) => archive::dupes::dupes(&conf, &dupes_opts),
+        Command::Thumbs => archive::thumbs::thumbs(&conf),
+        Command::Feeds => archive::feeds::write_feeds(&conf, None),
+        Command::TestWebhooks => network::test_webhooks(&conf).await,
+        Command::Export(export_opts) => archive::export::export(&conf, &export_opts),
+        Command::Stats(stats_opts) => {
+            let remote = if stats_opts.remote {
+                let token = config::read_token(&conf.cookies_file)?;
+                Some(network::remote_post_ids(&conf, &token).await?)
+            } else {
+                None
+            };
+            archive::stats::stats(&conf, &stats_opts, remote.as_ref())
+        }
+        Command::Get(get_opts) => {
+            let token = config::read_token(&conf.cookies_file)?;
+            network::get(&conf, &token, &get_opts).await
+        }
+    }
 }
@@ -0,0 +1,131 @@
+use futures::stream::{self, StreamExt};
+use reqwest::header;
+use std::collections::BTreeMap;
+
+use crate::archive::human_size;
+use crate::archive::metadata::FileKind;
+use crate::network::network_structs::*;
+use crate::network::{get_url, hls, Details};
+
+// what sync would download for a post
+pub struct Estimate {
+    artist: String,
+    url: String,
+    dir: String,
+    photos: usize,
+    videos: usize,
+    bytes: u64,
+    unknown_sizes: usize,
+    locked: bool,
+}
+
+#[derive(Default)]
+struct Totals {
+    posts: usize,
+    photos: usize,
+    videos: usize,
+    bytes: u64,
+    unknown_sizes: usize,
+    locked: usize,
+}
+
+impl Network {
+    // size of a file from a HEAD request, if the server reports one
+    async fn content_length(&self, url: &str) -> Option<u64> {
+        // a playlist's size says nothing about its video
+        if hls::is_playlist(url) {
+            return None;
+        }
+        let _permit = self.limits.media(url).await;
+        let resp = self.anon_client.head(url).send().await.ok()?;
+        if !resp.status().is_success() {
+            return None;
+        }
+        resp.headers()
+            .get(header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// What download_post would save for a post, without writing anything,
+    /// or None if it would be skipped
+    pub(super) async fn estimate_post(&self, post: Post) -> Result<Option<Estimate>, DownloadErr> {
+        let planned = match self.plan_post(post, Details::Unlocked).await? {
+            Some(planned) => planned,
+            None => return Ok(None),
+        };
+        let sizes = stream::iter(&planned.media_urls)
+            .map(|(_, _, url)| self.content_length(url))
+            .buffered(self.config.media_connections())
+            .collect::<Vec<_>>()
+            .await;
+        let count = |kind| {
+            planned
+                .media_urls
+                .iter()
+                .filter(|(k, _, _)| *k == kind)
+                .count()
+        };
+
+        Ok(Some(Estimate {
+            artist: planned.post.community.name.to_lowercase(),
+            url: get_url(&planned.post),
+            dir: planned.dir.clone(),
+            photos: count(FileKind::Photo),
+            videos: count(FileKind::Video),
+            bytes: sizes.iter().flatten().sum(),
+            unknown_sizes: sizes.iter().filter(|s| s.is_none()).count(),
+            locked: planned.post.locked,
+        }))
+    }
+}
+
+/// Print where each post would be saved, then what each artist would
+/// download in total
+pub fn report(mut estimates: Vec<Estimate>) {
+    estimates.sort_by(|a, b| a.dir.cmp(&b.dir));
+    let mut totals: BTreeMap<&str, Totals> = BTreeMap::new();
+    for estimate in &estimates {
+        println!(
+            "Would download {} to {}{}",
+            estimate.url,
+            estimate.dir,
+            if estimate.locked { " (locked)" } else { "" }
+        );
+        let total = totals.entry(&estimate.artist).or_default();
+        total.posts += 1;
+        total.photos += estimate.photos;
+        total.videos += estimate.videos;
+        total.bytes += estimate.bytes;
+        total.unknown_sizes += estimate.unknown_sizes;
+        total.locked += estimate.locked as usize;
+    }
+
+    if !estimates.is_empty() {
+        println!();
+    }
+    for (artist, total) in &totals {
+        println!(
+            "{}: {} new posts, {} photos, {} videos, about {}",
+            artist,
+            total.posts,
+            total.photos,
+            total.videos,
+            human_size(total.bytes)
+        );
+        if total.unknown_sizes > 0 {
+            println!("  plus {} files of unknown size", total.unknown_sizes);
+        }
+        if total.locked > 0 {
+            println!(
+                "  {} locked posts need a password, only what their preview shows is counted",
+                total.locked
+            );
+        }
+    }
+    if totals.is_empty() {
+        println!("Nothing new to download");
+    }
+}
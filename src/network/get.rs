@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::cli::GetOpts;
+use crate::config::Config;
+use crate::network::hook::HookRun;
+use crate::network::network_structs::*;
+use crate::network::notify::{Notification, Notifier};
+use crate::network::urls::POST_URL;
+use crate::network::{get_url, Details};
+
+// artist and post id from a post url, or from an id and --artist
+fn parse_target(target: &str, artist: Option<&str>) -> Result<(String, i64), String> {
+    if let Ok(id) = target.parse::<i64>() {
+        let artist =
+            artist.ok_or_else(|| format!("Error getting post {}: ids need --artist", target))?;
+        return Ok((artist.to_lowercase(), id));
+    }
+
+    let err = || format!("Error parsing post url {} (expected {})", target, POST_URL);
+    let path = target
+        .strip_prefix("https://")
+        .or_else(|| target.strip_prefix("http://"))
+        .unwrap_or(target);
+    let path = path.strip_prefix("www.").unwrap_or(path);
+    let path = path.strip_prefix("weverse.io/").ok_or_else(err)?;
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    match path.trim_end_matches('/').split('/').collect::<Vec<_>>()[..] {
+        [artist, "artist", id] => Ok((artist.to_lowercase(), id.parse().map_err(|_| err())?)),
+        _ => Err(err()),
+    }
+}
+
+/// Download specific posts given by url or id, from the command line and
+/// optionally a file or standard input
+pub async fn get(conf: &Config, token: &str, opts: &GetOpts) -> Result<(), String> {
+    let mut targets = opts.posts.clone();
+    if let Some(path) = &opts.file {
+        let contents = if path.as_os_str() == "-" {
+            let mut s = String::new();
+            std::io::stdin()
+                .read_to_string(&mut s)
+                .map_err(|e| format!("Error reading stdin: {}", e))?;
+            s
+        } else {
+            fs::read_to_string(path)
+                .map_err(|e| format!("Error reading {}: {}", path.display(), e))?
+        };
+        targets.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(String::from),
+        );
+    }
+    if targets.is_empty() {
+        return Err(String::from("No posts given"));
+    }
+
+    // check everything up front so a typo doesn't stop the run halfway
+    let posts = targets
+        .iter()
+        .map(|t| parse_target(t.trim(), opts.artist.as_deref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (artist, _) in &posts {
+        if !conf.artists.contains_key(artist) {
+            return Err(format!("Error: {} is not an artist in config.toml", artist));
+        }
+    }
+
+    println!("Getting artist ids...");
+    let n = Network::new(conf, token).await?;
+    let mtx = Arc::new(Mutex::new(0usize));
+    let notifier = Notifier::new(conf);
+    let mut changed = HashSet::new();
+    let mut seen = HashSet::new();
+    let mut failed = 0usize;
+    for (artist, id) in posts {
+        if !seen.insert(id) {
+            continue;
+        }
+        match n.get_post(&artist, id, mtx.clone()).await {
+            Ok(DownloadOk::Downloaded(post, dir)) => {
+                println!("Downloaded {}", get_url(&post));
+                if let Ok(post_type) = post.kind() {
+                    changed.insert((artist.clone(), post_type));
+                }
+                let hook = conf.artists[&artist]
+                    .post_download_hook
+                    .as_ref()
+                    .or(conf.post_download_hook.as_ref());
+                if let Some(hook) = hook {
+                    if let Err(e) = HookRun::new(hook, &post, &dir).run().await {
+                        println!("{}", e);
+                    }
+                }
+                if let Some(notifier) = &notifier {
+                    for e in notifier.notify(&Notification::new(&post, &dir)).await {
+                        println!("{}", e);
+                    }
+                }
+            }
+            Ok(DownloadOk::Skipped(post)) => {
+                println!(
+                    "Skipped {} (already archived or nothing to save)",
+                    get_url(&post)
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                println!("{}", e);
+            }
+        }
+    }
+
+    if conf.feeds.is_some() && !changed.is_empty() {
+        crate::archive::feeds::write_feeds(conf, Some(&changed))?;
+    }
+    if failed > 0 {
+        return Err(format!("{} posts failed", failed));
+    }
+    Ok(())
+}
+
+impl Network {
+    async fn get_post(
+        &self,
+        artist: &str,
+        id: i64,
+        mtx: Arc<Mutex<usize>>,
+    ) -> Result<DownloadOk, DownloadErr> {
+        let artist_id = *self
+            .artist_id_map
+            .get(artist)
+            .ok_or_else(|| DownloadErr::ArtistMapErr(artist.to_string()))?;
+        let mut stub = Post {
+            id,
+            community: Community {
+                id: artist_id,
+                name: artist.to_string(),
+            },
+            ..Default::default()
+        };
+
+        // only a refused request says whether the post is locked
+        let post = match self.download_post_info(&stub, mtx.clone()).await {
+            Err(DownloadErr::ResponseErr(_, _, code)) if code == reqwest::StatusCode::FORBIDDEN => {
+                stub.locked = true;
+                self.download_post_info(&stub, mtx).await
+            }
+            r => r,
+        }?;
+
+        match self.plan_post(post.clone(), Details::Known).await? {
+            Some(planned) => self.save_post(planned).await,
+            None => Ok(DownloadOk::Skipped(post)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(target: &str, artist: Option<&str>) -> Option<(String, i64)> {
+        parse_target(target, artist).ok()
+    }
+
+    #[test]
+    fn post_urls() {
+        let expected = Some((String::from("bts"), 12345));
+        for url in &[
+            "https://weverse.io/bts/artist/12345",
+            "https://weverse.io/bts/artist/12345/",
+            "https://weverse.io/bts/artist/12345?hl=en",
+            "https://weverse.io/bts/artist/12345/?hl=en#comments",
+            "http://www.weverse.io/bts/artist/12345",
+            "weverse.io/bts/artist/12345",
+            "https://weverse.io/BTS/artist/12345",
+        ] {
+            assert_eq!(target(url, None), expected, "{}", url);
+        }
+        // the url names the artist, not --artist
+        assert_eq!(
+            target("https://weverse.io/bts/artist/12345", Some("txt")),
+            expected
+        );
+    }
+
+    #[test]
+    fn bare_ids() {
+        assert_eq!(
+            target("12345", Some("BTS")),
+            Some((String::from("bts"), 12345))
+        );
+        assert_eq!(
+            parse_target("12345", None),
+            Err(String::from("Error getting post 12345: ids need --artist"))
+        );
+    }
+
+    #[test]
+    fn garbage() {
+        for bad in &[
+            "",
+            "hello",
+            "https://example.com/bts/artist/12345",
+            "https://weverse.io/bts/artist/",
+            "https://weverse.io/bts/artist/abc",
+            "https://weverse.io/bts/media/12345",
+            "https://weverse.io/bts/artist/12345/extra",
+            "https://weverse.io/bts",
+        ] {
+            assert!(parse_target(bad, Some("bts")).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use futures::stream::{self, StreamExt};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::time::Duration;
+
+use crate::network::network_structs::*;
+
+const SEGMENT_ATTEMPTS: u32 = 3;
+
+struct Variant {
+    bandwidth: u64,
+    url: Url,
+}
+
+struct MediaPlaylist {
+    init: Option<Url>,
+    segments: Vec<Url>,
+}
+
+pub fn is_playlist(url: &str) -> bool {
+    Url::parse(url)
+        .map(|u| u.path().to_lowercase().ends_with(".m3u8"))
+        .unwrap_or(false)
+}
+
+// value of an attribute in a tag such as #EXT-X-STREAM-INF:BANDWIDTH=1000,...
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag.split_once(':')?.1;
+    while !rest.is_empty() {
+        let (key, after) = rest.split_once('=')?;
+        let (value, after) = if let Some(quoted) = after.strip_prefix('"') {
+            let end = quoted.find('"')?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        if key.trim() == name {
+            return Some(value);
+        }
+        rest = after.trim_start_matches(',');
+    }
+    None
+}
+
+fn parse_variants(base: &Url, playlist: &str) -> Vec<Variant> {
+    let mut variants = Vec::new();
+    let mut lines = playlist.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        if !line.starts_with("#EXT-X-STREAM-INF") {
+            continue;
+        }
+        let bandwidth = attribute(line, "BANDWIDTH")
+            .and_then(|b| b.parse().ok())
+            .unwrap_or(0);
+        let uri = lines.find(|l| !l.is_empty() && !l.starts_with('#'));
+        if let Some(url) = uri.and_then(|u| base.join(u).ok()) {
+            variants.push(Variant { bandwidth, url });
+        }
+    }
+    variants
+}
+
+fn parse_media_playlist(base: &Url, playlist: &str) -> Result<MediaPlaylist, String> {
+    let mut init = None;
+    let mut segments = Vec::new();
+    for line in playlist.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if line.starts_with("#EXT-X-KEY") {
+            if attribute(line, "METHOD") != Some("NONE") {
+                return Err(String::from("encrypted streams are not supported"));
+            }
+        } else if line.starts_with("#EXT-X-BYTERANGE") {
+            return Err(String::from("byte range segments are not supported"));
+        } else if line.starts_with("#EXT-X-MAP") {
+            let uri = attribute(line, "URI").ok_or("EXT-X-MAP without URI")?;
+            init = Some(base.join(uri).map_err(|e| e.to_string())?);
+        } else if !line.starts_with('#') {
+            segments.push(base.join(line).map_err(|e| e.to_string())?);
+        }
+    }
+    if segments.is_empty() {
+        return Err(String::from("playlist has no segments"));
+    }
+    Ok(MediaPlaylist { init, segments })
+}
+
+// highest bandwidth variant, optionally capped by max_bandwidth
+fn choose_variant(variants: Vec<Variant>, max_bandwidth: Option<u64>) -> Option<Variant> {
+    let (allowed, too_large): (Vec<_>, Vec<_>) = variants
+        .into_iter()
+        .partition(|v| max_bandwidth.is_none_or(|max| v.bandwidth <= max));
+    match allowed.into_iter().max_by_key(|v| v.bandwidth) {
+        Some(v) => Some(v),
+        None => too_large.into_iter().min_by_key(|v| v.bandwidth),
+    }
+}
+
+impl Network {
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, DownloadErr> {
+        let _permit = self.limits.media(url).await;
+        let mut resp = self
+            .anon_client
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| DownloadErr::RequestErr(url.to_string(), e))?;
+        if !resp.status().is_success() {
+            return Err(DownloadErr::HlsErr(
+                url.to_string(),
+                format!("response {}", resp.status()),
+            ));
+        }
+        let expected_len = resp.content_length();
+
+        let mut data = Vec::new();
+        while let Some(chunk) = resp
+            .chunk()
+            .await
+            .map_err(|e| DownloadErr::ResponseBytesErr(url.to_string(), e))?
+        {
+            self.limits.throttle(chunk.len()).await;
+            data.extend_from_slice(&chunk);
+        }
+        if let Some(expected) = expected_len {
+            if expected != data.len() as u64 {
+                return Err(DownloadErr::ResponseLengthErr(
+                    url.to_string(),
+                    expected,
+                    data.len() as u64,
+                ));
+            }
+        }
+        Ok(data)
+    }
+
+    async fn fetch_with_retry(&self, url: &str) -> Result<Vec<u8>, DownloadErr> {
+        let mut attempt = 1;
+        loop {
+            match self.fetch(url).await {
+                Ok(data) => return Ok(data),
+                Err(e) if attempt >= SEGMENT_ATTEMPTS => return Err(e),
+                Err(_) => {
+                    tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn fetch_playlist(&self, url: &Url) -> Result<String, DownloadErr> {
+        let data = self.fetch_with_retry(url.as_str()).await?;
+        String::from_utf8(data)
+            .map_err(|_| DownloadErr::HlsErr(url.to_string(), String::from("invalid playlist")))
+    }
+
+    // download an HLS stream into a single file at save_stem plus the
+    // returned extension, .mp4 for fragmented mp4 streams and .ts otherwise
+    pub(super) async fn download_hls(
+        &self,
+        url: &str,
+        save_stem: &str,
+    ) -> Result<(String, u64, String), DownloadErr> {
+        let hls_err = |u: &Url, e: String| DownloadErr::HlsErr(u.to_string(), e);
+        let mut playlist_url =
+            Url::parse(url).map_err(|e| DownloadErr::HlsErr(url.to_string(), e.to_string()))?;
+        let mut playlist = self.fetch_playlist(&playlist_url).await?;
+
+        // pick a variant from a master playlist
+        if playlist.contains("#EXT-X-STREAM-INF") {
+            let variants = parse_variants(&playlist_url, &playlist);
+            let variant = choose_variant(variants, self.config.hls_max_bandwidth)
+                .ok_or_else(|| hls_err(&playlist_url, String::from("no variants")))?;
+            playlist_url = variant.url;
+            playlist = self.fetch_playlist(&playlist_url).await?;
+        }
+
+        let media = parse_media_playlist(&playlist_url, &playlist)
+            .map_err(|e| hls_err(&playlist_url, e))?;
+        let ext = if media.init.is_some() { ".mp4" } else { ".ts" };
+
+        // only a complete stream is moved into place
+        let part_path = format!("{}.part", save_stem);
+        let mut buffer = File::create(&part_path)
+            .map_err(|e| DownloadErr::FileCreateErr(part_path.clone(), e))?;
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+
+        // fetch segments concurrently but write them in order
+        let parts = media.init.iter().chain(media.segments.iter());
+        let mut segments = stream::iter(parts)
+            .map(|u| self.fetch_with_retry(u.as_str()))
+            .buffered(self.config.media_connections());
+        while let Some(data) = segments.next().await {
+            let data = data?;
+            buffer
+                .write_all(&data)
+                .map_err(|e| DownloadErr::FileWriteErr(part_path.clone(), e))?;
+            hasher.update(&data);
+            size += data.len() as u64;
+        }
+
+        if size == 0 {
+            return Err(DownloadErr::EmptyResponseErr(url.to_string()));
+        }
+        std::mem::drop(buffer);
+        let save_path = format!("{}{}", save_stem, ext);
+        fs::rename(&part_path, &save_path).map_err(|e| DownloadErr::RenameErr(part_path, e))?;
+        Ok((ext.to_string(), size, format!("{:x}", hasher.finalize())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://cdn.example.com/video/1/master.m3u8?token=abc").unwrap()
+    }
+
+    fn variant(bandwidth: u64) -> Variant {
+        Variant {
+            bandwidth,
+            url: base().join(&format!("{}.m3u8", bandwidth)).unwrap(),
+        }
+    }
+
+    #[test]
+    fn attributes() {
+        let tag = r#"#EXT-X-STREAM-INF:BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2",RESOLUTION=1280x720"#;
+        assert_eq!(attribute(tag, "BANDWIDTH"), Some("1280000"));
+        assert_eq!(attribute(tag, "CODECS"), Some("avc1.4d401f,mp4a.40.2"));
+        assert_eq!(attribute(tag, "RESOLUTION"), Some("1280x720"));
+        assert_eq!(attribute(tag, "FRAME-RATE"), None);
+        assert_eq!(attribute("#EXTM3U", "BANDWIDTH"), None);
+    }
+
+    #[test]
+    fn variants() {
+        let playlist = r#"#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=800000,CODECS="avc1.4d401e,mp4a.40.2"
+low/index.m3u8
+
+#EXT-X-STREAM-INF:RESOLUTION=1920x1080
+# comment
+https://other.example.com/high/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2500000
+/abs/mid.m3u8
+"#;
+        let variants = parse_variants(&base(), playlist);
+        let found: Vec<(u64, &str)> = variants
+            .iter()
+            .map(|v| (v.bandwidth, v.url.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (800000, "https://cdn.example.com/video/1/low/index.m3u8"),
+                // missing BANDWIDTH
+                (0, "https://other.example.com/high/index.m3u8"),
+                (2500000, "https://cdn.example.com/abs/mid.m3u8"),
+            ]
+        );
+    }
+
+    #[test]
+    fn media_playlist() {
+        let playlist = r#"#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-KEY:METHOD=NONE
+#EXT-X-MAP:URI="init.mp4"
+#EXTINF:4.0,
+seg0.m4s
+#EXTINF:4.0,
+https://other.example.com/seg1.m4s
+#EXT-X-ENDLIST
+"#;
+        let media = parse_media_playlist(&base(), playlist).unwrap();
+        assert_eq!(
+            media.init.as_ref().map(Url::as_str),
+            Some("https://cdn.example.com/video/1/init.mp4")
+        );
+        let segments: Vec<&str> = media.segments.iter().map(Url::as_str).collect();
+        assert_eq!(
+            segments,
+            vec![
+                "https://cdn.example.com/video/1/seg0.m4s",
+                "https://other.example.com/seg1.m4s"
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_media_playlists() {
+        let encrypted = "#EXT-X-KEY:METHOD=AES-128,URI=\"key\"\nseg0.ts\n";
+        assert!(parse_media_playlist(&base(), encrypted).is_err());
+        let ranges = "#EXT-X-BYTERANGE:1000@0\nseg0.ts\n";
+        assert!(parse_media_playlist(&base(), ranges).is_err());
+        let empty = "#EXTM3U\n#EXT-X-ENDLIST\n";
+        assert!(parse_media_playlist(&base(), empty).is_err());
+    }
+
+    #[test]
+    fn chosen_variant() {
+        let bandwidths = |max| {
+            choose_variant(vec![variant(800), variant(2500), variant(1200)], max)
+                .map(|v| v.bandwidth)
+        };
+        assert_eq!(bandwidths(None), Some(2500));
+        assert_eq!(bandwidths(Some(2000)), Some(1200));
+        assert_eq!(bandwidths(Some(1200)), Some(1200));
+        // nothing fits, so the smallest
+        assert_eq!(bandwidths(Some(100)), Some(800));
+        assert!(choose_variant(Vec::new(), None).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+use std::process::Stdio;
+use tokio::process::Command;
+
+use crate::archive;
+use crate::network::network_structs::*;
+
+// what a post download hook runs for a newly archived post
+#[derive(Debug)]
+pub struct HookRun {
+    command: String,
+    post_dir: String,
+    post_id: i64,
+    artist: String,
+    post_type: String,
+    post_url: String,
+}
+
+impl HookRun {
+    pub fn new(command: &str, post: &Post, dir: &str) -> Self {
+        HookRun {
+            command: command.to_string(),
+            post_dir: dir.to_string(),
+            post_id: post.id,
+            artist: post.community.name.to_lowercase(),
+            post_type: post.kind().map_or("unknown", PostType::name).to_string(),
+            post_url: super::get_url(post),
+        }
+    }
+
+    /// Run the hook through the shell, describing the post in environment
+    /// variables
+    pub async fn run(&self) -> Result<(), String> {
+        let mut command = if cfg!(windows) {
+            let mut c = Command::new("cmd");
+            c.arg("/C");
+            c
+        } else {
+            let mut c = Command::new("sh");
+            c.arg("-c");
+            c
+        };
+        let status = command
+            .arg(&self.command)
+            .env("WEVERSE_POST_DIR", &self.post_dir)
+            .env("WEVERSE_POST_ID", self.post_id.to_string())
+            .env("WEVERSE_ARTIST", &self.artist)
+            .env("WEVERSE_POST_TYPE", &self.post_type)
+            .env("WEVERSE_POST_URL", &self.post_url)
+            .env("WEVERSE_METADATA", archive::metadata_path(&self.post_dir))
+            // passwords for locked posts are read from stdin while hooks run
+            .stdin(Stdio::null())
+            .status()
+            .await
+            .map_err(|e| format!("Error running hook for {}: {}", self.post_dir, e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("Hook for {} failed: {}", self.post_dir, status))
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+use tokio::time::{self, Instant};
+
+use crate::config::Config;
+
+// connection and bandwidth limits shared by every request
+pub struct Limits {
+    api: Semaphore,
+    media: Semaphore,
+    per_host: Option<usize>,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+    bandwidth: Option<u64>,
+    next_free: Mutex<Instant>,
+}
+
+pub struct Permit<'a> {
+    _pool: SemaphorePermit<'a>,
+    _host: Option<OwnedSemaphorePermit>,
+}
+
+impl Limits {
+    pub fn new(config: &Config) -> Self {
+        Limits {
+            api: Semaphore::new(config.api_connections()),
+            media: Semaphore::new(config.media_connections()),
+            per_host: config.max_connections_per_host.map(|n| n.max(1)),
+            hosts: Mutex::new(HashMap::new()),
+            bandwidth: config.bandwidth_limit.filter(|&b| b > 0),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub async fn api(&self, url: &str) -> Permit<'_> {
+        self.acquire(&self.api, url).await
+    }
+
+    pub async fn media(&self, url: &str) -> Permit<'_> {
+        self.acquire(&self.media, url).await
+    }
+
+    async fn acquire<'a>(&'a self, pool: &'a Semaphore, url: &str) -> Permit<'a> {
+        let host = match self.per_host {
+            Some(n) => {
+                let host = reqwest::Url::parse(url)
+                    .ok()
+                    .and_then(|u| u.host_str().map(|h| h.to_string()))
+                    .unwrap_or_default();
+                let semaphore = self
+                    .hosts
+                    .lock()
+                    .unwrap()
+                    .entry(host)
+                    .or_insert_with(|| Arc::new(Semaphore::new(n)))
+                    .clone();
+                Some(semaphore.acquire_owned().await.unwrap())
+            }
+            None => None,
+        };
+        Permit {
+            _pool: pool.acquire().await.unwrap(),
+            _host: host,
+        }
+    }
+
+    // wait until `bytes` more bytes fit under the global bandwidth limit
+    pub async fn throttle(&self, bytes: usize) {
+        let rate = match self.bandwidth {
+            Some(r) => r,
+            None => return,
+        };
+        let until = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let start = std::cmp::max(*next_free, Instant::now());
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+            *next_free
+        };
+        time::sleep_until(until).await;
+    }
+}
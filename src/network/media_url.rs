@@ -0,0 +1,148 @@
+use reqwest::{header, Url};
+
+use crate::archive::media;
+use crate::network::network_structs::*;
+
+// query parameters weverse image cdns use to resize or recompress images
+const RESIZE_PARAMS: &[&str] = &[
+    "type", "w", "h", "width", "height", "quality", "q", "resize", "size",
+];
+
+fn is_weverse_cdn(url: &Url) -> bool {
+    let host = url.host_str().unwrap_or_default();
+    ["weverse.io", "phinf.naver.net", "pstatic.net"]
+        .iter()
+        .any(|h| host == *h || host.ends_with(&format!(".{}", h)))
+}
+
+/// Urls that may serve an image, the original without resizing first
+pub fn candidates(url: &str) -> Vec<String> {
+    let parsed = match Url::parse(url) {
+        Ok(u) if is_weverse_cdn(&u) => u,
+        _ => return vec![url.to_string()],
+    };
+
+    let kept: Vec<(String, String)> = parsed
+        .query_pairs()
+        .filter(|(k, _)| !RESIZE_PARAMS.contains(&k.to_lowercase().as_str()))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    let mut original = parsed.clone();
+    if kept.is_empty() {
+        original.set_query(None);
+    } else {
+        original.query_pairs_mut().clear().extend_pairs(kept);
+    }
+
+    if original == parsed {
+        vec![url.to_string()]
+    } else {
+        vec![original.to_string(), url.to_string()]
+    }
+}
+
+impl Network {
+    async fn image_dimensions(&self, url: &str) -> Option<(u32, u32)> {
+        let _permit = self.limits.media(url).await;
+        let mut resp = self
+            .anon_client
+            .get(url)
+            .header(header::RANGE, format!("bytes=0-{}", media::HEADER_LEN - 1))
+            .send()
+            .await
+            .ok()?;
+        if !resp.status().is_success() {
+            return None;
+        }
+        // servers that ignore the range send the whole image, stop early
+        let mut head = Vec::new();
+        while let Some(chunk) = resp.chunk().await.ok()? {
+            head.extend_from_slice(&chunk);
+            if head.len() as u64 >= media::HEADER_LEN {
+                break;
+            }
+        }
+        media::dimensions(&head)
+    }
+
+    // the highest resolution variant of an image, preferring the original
+    pub(super) async fn best_variant(&self, url: &str) -> String {
+        let candidates = candidates(url);
+        if candidates.len() == 1 {
+            return url.to_string();
+        }
+
+        let mut best: Option<(u64, &String)> = None;
+        for candidate in &candidates {
+            if let Some((w, h)) = self.image_dimensions(candidate).await {
+                let area = w as u64 * h as u64;
+                if best.is_none_or(|(a, _)| area > a) {
+                    best = Some((area, candidate));
+                }
+            }
+        }
+        // keep the url we were given if no candidate could be checked
+        best.map_or_else(|| url.to_string(), |(_, c)| c.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_resize_params_on_weverse_cdns() {
+        for host in &[
+            "weverse.io",
+            "cdn-contents.weverse.io",
+            "phinf.naver.net",
+            "post-phinf.pstatic.net",
+        ] {
+            let url = format!("https://{}/20210613/photo.jpg?type=w1080&quality=80", host);
+            assert_eq!(
+                candidates(&url),
+                vec![format!("https://{}/20210613/photo.jpg", host), url.clone()],
+                "{}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_other_params() {
+        let url = "https://phinf.pstatic.net/photo.jpg?TYPE=f&token=abc&W=100";
+        assert_eq!(
+            candidates(url),
+            vec![
+                String::from("https://phinf.pstatic.net/photo.jpg?token=abc"),
+                String::from(url)
+            ]
+        );
+    }
+
+    #[test]
+    fn original_urls_are_left_alone() {
+        let url = "https://phinf.pstatic.net/photo.jpg?token=abc";
+        assert_eq!(candidates(url), vec![String::from(url)]);
+        let url = "https://phinf.pstatic.net/photo.jpg";
+        assert_eq!(candidates(url), vec![String::from(url)]);
+    }
+
+    #[test]
+    fn other_hosts_pass_through() {
+        for url in &[
+            "https://example.com/photo.jpg?type=w1080",
+            "https://notweverse.io/photo.jpg?w=100",
+            "https://weverse.io.example.com/photo.jpg?w=100",
+            "not a url?type=w1",
+        ] {
+            assert_eq!(candidates(url), vec![url.to_string()]);
+        }
+    }
+
+    #[test]
+    fn original_url_is_the_last_fallback() {
+        let url = "https://weverse.io/photo.jpg?type=w1&q=10";
+        assert_eq!(candidates(url).last(), Some(&String::from(url)));
+    }
+}
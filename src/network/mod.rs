@@ -1,20 +1,43 @@
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc as queue;
 use futures::stream::{self, StreamExt};
+use futures::SinkExt;
 use reqwest::header;
 use serde::Deserialize;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::io;
-
-use crate::config::Config;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::archive::metadata::{self, FileKind, MediaFile, PostMetadata};
+use crate::archive::store::Store;
+use crate::archive::{self, embed, media, touch};
+use crate::cli::SyncOpts;
+use crate::config::{self, ArtistConfig, Config, ContentKind, DateRange};
+use crate::network::hook::HookRun;
+use crate::network::limits::Limits;
 use crate::network::network_structs::*;
+use crate::network::notify::{Notification, Notifier};
 use crate::network::urls::*;
 
-mod network_structs;
+pub use get::get;
+pub use notify::test_webhooks;
+pub use repair::repair;
+
+mod dry_run;
+mod get;
+mod hls;
+mod hook;
+mod limits;
+mod media_url;
+pub mod network_structs;
+mod notify;
+mod repair;
 mod urls;
 
 fn get_prefix(post: &Post) -> String {
@@ -27,7 +50,7 @@ fn get_prefix(post: &Post) -> String {
 }
 
 fn post_dir_exists(dir: impl AsRef<Path>, prefix: &str) -> bool {
-    let start = prefix.splitn(3, "-").take(2).collect::<Vec<_>>().join("-");
+    let start = prefix.splitn(3, '-').take(2).collect::<Vec<_>>().join("-");
     let paths = match fs::read_dir(dir) {
         Ok(p) => p,
         Err(_) => return false,
@@ -41,7 +64,7 @@ fn post_dir_exists(dir: impl AsRef<Path>, prefix: &str) -> bool {
         let cur_start = cur_path
             .file_name()
             .to_string_lossy()
-            .splitn(3, "-")
+            .splitn(3, '-')
             .take(2)
             .collect::<Vec<_>>()
             .join("-");
@@ -53,7 +76,7 @@ fn post_dir_exists(dir: impl AsRef<Path>, prefix: &str) -> bool {
     false
 }
 
-fn get_url(post: &Post) -> String {
+pub(crate) fn get_url(post: &Post) -> String {
     POST_URL
         .replace(
             "{artist}",
@@ -62,71 +85,384 @@ fn get_url(post: &Post) -> String {
         .replace("{post_id}", post.id.to_string().as_str())
 }
 
-pub async fn download(conf: &Config, token: &str) -> Result<(), String> {
-    let n = Network::new(&conf, &token).await?;
+pub(crate) fn get_download_dir(artist_config: &ArtistConfig, post_type: &PostType) -> String {
+    match post_type {
+        PostType::Artist => artist_config.artist_download_path.clone(),
+        PostType::Moment => artist_config.moments_download_path.clone(),
+    }
+    .unwrap_or_else(|| String::from("posts"))
+}
 
-    // get a list of all posts to download
-    println!("Getting all post info...");
-    let artist_iter = conf
-        .artists
-        .iter()
-        .map(|(k, v)| n.download_posts_info(&v.recent_artist, k.to_owned(), PostType::Artist));
-    let moments_iter = conf
+// record video dimensions and duration alongside the downloaded files
+fn set_video_info(post: &Post, files: &mut [MediaFile]) {
+    for file in files.iter_mut().filter(|f| f.kind == FileKind::Video) {
+        if let Some(video) = post.attached_videos.iter().flatten().nth(file.index) {
+            file.width = video.width;
+            file.height = video.height;
+            file.duration = video.duration;
+        }
+    }
+}
+
+// write post details into a downloaded image
+fn embed_metadata(dir: &str, post: &Post, file: &mut MediaFile) -> Result<(), DownloadErr> {
+    if file.kind == FileKind::Video {
+        return Ok(());
+    }
+    let path = format!("{}/{}", dir, file.name);
+    let date = DateTime::parse_from_rfc3339(&post.created_at).ok();
+    let tags = embed::Tags {
+        date,
+        artist: &post.community_user.nickname,
+        description: post.body.as_deref().unwrap_or_default(),
+        source: &get_url(post),
+    };
+    if embed::embed(&path, &tags).map_err(DownloadErr::MetadataErr)? {
+        file.size = fs::metadata(&path)
+            .map_err(|e| DownloadErr::MetadataErr(format!("{}: {}", path, e)))?
+            .len();
+        file.sha256 = media::sha256_file(&path)
+            .map_err(|e| DownloadErr::MetadataErr(format!("{}: {}", path, e)))?;
+    }
+    Ok(())
+}
+
+// move a downloaded file into the content-addressed store and link it back,
+// recording it as being in final_dir once the post is finished. With
+// embed_metadata, images are stored by the hash of their downloaded bytes and
+// tagged for the post that stores them first. Returns the path of a file that
+// is new to the store
+fn store_file(
+    store: &Store,
+    embed: bool,
+    dir: &str,
+    final_dir: &str,
+    post: &Post,
+    file: &mut MediaFile,
+) -> Result<Option<PathBuf>, DownloadErr> {
+    let path = Path::new(dir).join(&file.name);
+    let archived_path = Path::new(final_dir).join(&file.name);
+    let key = file.sha256.clone();
+    if embed && !store.contains(&path, &key) {
+        embed_metadata(dir, post, file)?;
+    }
+    let err = |e: std::io::Error| DownloadErr::MetadataErr(format!("{}: {}", path.display(), e));
+    let (stored, duplicate) = store
+        .link(&path, &key, &archived_path, post.id)
+        .map_err(err)?;
+    if embed && duplicate {
+        // linked to a copy tagged for another post
+        file.size = fs::metadata(&path).map_err(err)?.len();
+        file.sha256 = media::sha256_file(&path).map_err(err)?;
+    }
+    file.stored = Some(stored.to_string_lossy().to_string());
+    Ok(if duplicate { None } else { Some(stored) })
+}
+
+// how plan_post gets the full details of locked posts and posts with videos
+enum Details {
+    // request them, asking for the password of locked posts
+    Fetch(Arc<Mutex<usize>>),
+    // request them for unlocked posts only, planning locked posts from what
+    // the feed shows
+    Unlocked,
+    // the post already has them
+    Known,
+}
+
+// a post download_post would save, and where
+struct Planned {
+    post: Post,
+    prefix: String,
+    dir: String,
+    saved: Vec<ContentKind>,
+    media_urls: Vec<(FileKind, usize, String)>,
+}
+
+fn write_content(dir: &str, prefix: &str, post: &Post) -> Result<(), DownloadErr> {
+    let save_path = format!("{}/{}-content.txt", dir, prefix);
+    let body = match &post.body {
+        Some(v) => v.as_str(),
+        None => "",
+    };
+    let content = format!(
+        "https://weverse.io/{}/artist/{}\n{} ({}):\n{}",
+        post.community.name.to_lowercase(),
+        post.id,
+        &post.community_user.nickname,
+        &post.created_at,
+        body
+    );
+    let mut buffer = File::create(save_path.as_str())
+        .map_err(|e| DownloadErr::FileCreateErr(save_path.clone(), e))?;
+    buffer
+        .write_all(content.as_bytes())
+        .map_err(|e| DownloadErr::FileWriteErr(save_path.clone(), e))
+}
+
+pub async fn download(conf: &Config, token: &str, opts: &SyncOpts) -> Result<(), String> {
+    // command line content filters apply to every artist
+    let mut conf = conf.clone();
+    for artist_config in conf.artists.values_mut() {
+        if opts.save.is_some() {
+            artist_config.save = opts.save.clone();
+        }
+        if opts.keep_posts_with.is_some() {
+            artist_config.keep_posts_with = opts.keep_posts_with.clone();
+        }
+    }
+    let conf = &conf;
+    println!("Getting artist ids...");
+    let n = Network::new(conf, token).await?;
+
+    // stop paging once this many archived posts are seen in a row
+    let stop_after = if opts.full || conf.stop_after_archived == 0 {
+        None
+    } else {
+        Some(conf.stop_after_archived)
+    };
+
+    // crawled posts are queued for download as each page arrives, crawling
+    // waits whenever the queue is full
+    println!("Getting post info...");
+    let (queue_tx, queue_rx) = queue::channel::<Post>(conf.download_queue_size);
+    let ranges = conf
         .artists
         .iter()
-        .map(|(k, v)| n.download_posts_info(&v.recent_moments, k.to_owned(), PostType::Moment));
-    let posts = stream::iter(artist_iter.chain(moments_iter))
-        .buffer_unordered(conf.max_connections)
-        .collect::<Vec<Result<Vec<_>, _>>>()
-        .await
-        .into_iter()
-        .collect::<Result<Vec<Vec<_>>, _>>()
-        .map_err(|e| format!("Error collecting posts info: {}", e))?
-        .into_iter()
-        .flatten();
+        .map(|(k, v)| {
+            Ok((
+                k,
+                v.date_range(opts.since, opts.until)
+                    .map_err(|e| format!("{} for {}", e, k))?,
+            ))
+        })
+        .collect::<Result<HashMap<_, _>, String>>()?;
+    let artist_iter = conf.artists.iter().map(|(k, v)| {
+        n.download_posts_info(
+            &v.recent_artist,
+            k.to_owned(),
+            PostType::Artist,
+            stop_after,
+            ranges[k],
+            queue_tx.clone(),
+        )
+    });
+    let moments_iter = conf.artists.iter().map(|(k, v)| {
+        n.download_posts_info(
+            &v.recent_moments,
+            k.to_owned(),
+            PostType::Moment,
+            stop_after,
+            ranges[k],
+            queue_tx.clone(),
+        )
+    });
+    let crawling = stream::iter(artist_iter.chain(moments_iter).collect::<Vec<_>>())
+        .buffer_unordered(conf.api_connections())
+        .collect::<Vec<Result<Crawl, _>>>();
+    std::mem::drop(queue_tx);
+
+    // plan every post without writing anything
+    if opts.dry_run {
+        let estimating = queue_rx
+            .map(|p| n.estimate_post(p))
+            .buffer_unordered(conf.media_connections())
+            .collect::<Vec<_>>();
+        let (crawls, estimates) = futures::join!(crawling, estimating);
+        crawls
+            .into_iter()
+            .collect::<Result<Vec<Crawl>, _>>()
+            .map_err(|e| format!("Error collecting posts info: {}", e))?;
+        let mut planned = Vec::new();
+        for estimate in estimates {
+            match estimate {
+                Ok(Some(e)) => planned.push(e),
+                Ok(None) => (),
+                Err(e) => println!("{}", e),
+            }
+        }
+        dry_run::report(planned);
+        return Ok(());
+    }
 
     let mtx = Arc::new(Mutex::new(0usize));
-    let posts_iter = posts.map(|p| n.download_post(p, mtx.clone()));
-    let mut downloads = stream::iter(posts_iter).buffer_unordered(conf.max_connections);
+    let mut downloads = queue_rx
+        .map(|p| n.download_post(p, mtx.clone()))
+        .buffer_unordered(conf.media_connections());
 
-    // spawn a new thread to manage printing to stdout
-    let (tx, rx) = std::sync::mpsc::channel::<String>();
-    {
+    // spawn a new task to manage printing to stdout
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let printing = {
         let mtx = mtx.clone();
         tokio::spawn(async move {
-            loop {
-                match rx.recv() {
-                    Ok(s) => {
-                        let guard = mtx.lock().unwrap();
-                        println!("{}", s);
-                        std::mem::drop(guard);
-                    }
-                    Err(_) => return,
-                }
+            while let Some(s) = rx.recv().await {
+                let guard = mtx.lock().await;
+                println!("{}", s);
+                std::mem::drop(guard);
             }
-        });
-    }
+        })
+    };
 
-    while let Some(result) = downloads.next().await {
-        match result {
-            Ok(DownloadOk::Downloaded(p)) => {
-                tx.send(format!("Downloaded {}", get_url(&p))).unwrap()
+    // artists and post types with new posts, whose feeds need updating
+    let mut changed = HashSet::new();
+    // webhooks are notified one post at a time in the background, so
+    // downloads keep going without flooding rate limited webhooks
+    let (notify_tx, mut notify_rx) = mpsc::unbounded_channel::<Notification>();
+    let notifications = Notifier::new(conf).map(|notifier| {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(notification) = notify_rx.recv().await {
+                for e in notifier.notify(&notification).await {
+                    tx.send(e).unwrap();
+                }
+            }
+        })
+    });
+    let mut downloaded = 0usize;
+    let mut failed = 0usize;
+
+    // post download hooks run one at a time, in the order posts finish
+    let (hook_tx, mut hook_rx) = mpsc::unbounded_channel::<HookRun>();
+    let hooks = {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(hook) = hook_rx.recv().await {
+                if let Err(e) = hook.run().await {
+                    tx.send(e).unwrap();
+                }
             }
-            Err(e) => {
-                if let DownloadErr::ResponseErr(_, post, code) = e {
-                    if code == reqwest::StatusCode::FORBIDDEN {
-                        tx.send(format!("Wrong password for {}", get_url(&post)))
-                            .unwrap();
+        })
+    };
+    let downloading = async {
+        while let Some(result) = downloads.next().await {
+            match result {
+                Ok(DownloadOk::Downloaded(p, dir)) => {
+                    downloaded += 1;
+                    tx.send(format!("Downloaded {}", get_url(&p))).unwrap();
+                    if let Ok(post_type) = p.kind() {
+                        changed.insert((p.community.name.to_lowercase(), post_type));
+                    }
+                    let artist_config = conf.artists.get(&p.community.name.to_lowercase());
+                    let hook = artist_config
+                        .and_then(|a| a.post_download_hook.as_ref())
+                        .or(conf.post_download_hook.as_ref());
+                    if let Some(hook) = hook {
+                        hook_tx.send(HookRun::new(hook, &p, &dir)).unwrap();
+                    }
+                    if notifications.is_some() {
+                        notify_tx.send(Notification::new(&p, &dir)).unwrap();
                     }
                 }
+                Err(DownloadErr::ResponseErr(_, Some(post), code))
+                    if code == reqwest::StatusCode::FORBIDDEN =>
+                {
+                    failed += 1;
+                    tx.send(format!("Wrong password for {}", get_url(&post)))
+                        .unwrap();
+                }
+                Err(e) => {
+                    failed += 1;
+                    tx.send(e.to_string()).unwrap();
+                }
+                Ok(DownloadOk::Skipped(_)) => (),
             }
-            _ => (),
         }
+    };
+
+    let (crawls, _) = futures::join!(crawling, downloading);
+    std::mem::drop(notify_tx);
+    if let Some(notifications) = notifications {
+        let _ = notifications.await;
+    }
+    std::mem::drop(hook_tx);
+    let _ = hooks.await;
+    std::mem::drop(tx);
+    let _ = printing.await;
+    println!("Downloaded {} posts, {} failed", downloaded, failed);
+    let crawls = crawls
+        .into_iter()
+        .collect::<Result<Vec<Crawl>, _>>()
+        .map_err(|e| format!("Error collecting posts info: {}", e))?;
+
+    // remote post ids for each download directory, only usable if every
+    // feed saving to that directory was crawled completely
+    let mut remote_ids: HashMap<String, (bool, HashSet<i64>)> = HashMap::new();
+    for crawl in crawls {
+        let entry = remote_ids
+            .entry(crawl.download_dir)
+            .or_insert_with(|| (true, HashSet::new()));
+        entry.0 &= crawl.complete;
+        entry.1.extend(crawl.post_ids);
+    }
+
+    // mark posts that are archived but no longer exist upstream
+    for (dir, (complete, ids)) in remote_ids {
+        if complete {
+            archive::reconcile::reconcile(&dir, &ids)?;
+        }
+    }
+
+    if conf.feeds.is_some() && !changed.is_empty() {
+        archive::feeds::write_feeds(conf, Some(&changed))?;
     }
 
     Ok(())
 }
 
+/// Ids of the remote posts sync would archive, by lowercase artist name and
+/// post type, crawling every feed completely
+pub async fn remote_post_ids(
+    conf: &Config,
+    token: &str,
+) -> Result<HashMap<(String, PostType), HashSet<i64>>, String> {
+    let n = Network::new(conf, token).await?;
+    let n = &n;
+    let ranges = conf
+        .artists
+        .iter()
+        .map(|(k, v)| {
+            Ok((
+                k,
+                v.date_range(None, None)
+                    .map_err(|e| format!("{} for {}", e, k))?,
+            ))
+        })
+        .collect::<Result<HashMap<_, _>, String>>()?;
+
+    let (queue_tx, queue_rx) = queue::channel::<Post>(conf.download_queue_size);
+    let mut crawls = Vec::new();
+    for artist in conf.artists.keys() {
+        for &post_type in &[PostType::Artist, PostType::Moment] {
+            crawls.push(n.download_posts_info(
+                &None,
+                artist.to_owned(),
+                post_type,
+                None,
+                ranges[artist],
+                queue_tx.clone(),
+            ));
+        }
+    }
+    let crawling = stream::iter(crawls)
+        .buffer_unordered(conf.api_connections())
+        .collect::<Vec<Result<Crawl, _>>>();
+    std::mem::drop(queue_tx);
+
+    let collecting = queue_rx.fold(HashMap::new(), |mut ids, post| {
+        if let Ok(post_type) = post.kind() {
+            ids.entry((post.community.name.to_lowercase(), post_type))
+                .or_insert_with(HashSet::new)
+                .insert(post.id);
+        }
+        futures::future::ready(ids)
+    });
+    let (crawls, ids) = futures::join!(crawling, collecting);
+    for crawl in crawls {
+        crawl.map_err(|e| format!("Error collecting posts info: {}", e))?;
+    }
+    Ok(ids)
+}
+
 async fn get_artist_id(client: &reqwest::Client) -> Result<HashMap<String, i64>, String> {
     #[derive(Deserialize)]
     struct InfoResp {
@@ -177,7 +513,6 @@ impl<'a> Network {
         let anon_client = reqwest::Client::builder()
             .build()
             .map_err(|e| format!("Error building request client: {}", e))?;
-        println!("Getting artist ids...");
         let artist_id_map = get_artist_id(&anon_client).await?;
 
         let n = Network {
@@ -185,6 +520,7 @@ impl<'a> Network {
             client,
             anon_client,
             artist_id_map,
+            limits: Arc::new(Limits::new(config)),
         };
         Ok(n)
     }
@@ -194,11 +530,17 @@ impl<'a> Network {
         recent: &Option<isize>,
         artist: String,
         post_type: PostType,
-    ) -> Result<Vec<Post>, DownloadErr> {
+        stop_after: Option<usize>,
+        range: DateRange,
+        mut queue: queue::Sender<Post>,
+    ) -> Result<Crawl, DownloadErr> {
         let artist_id = self
             .artist_id_map
             .get(&artist[..])
-            .ok_or(DownloadErr::ArtistMapErr(artist))?;
+            .ok_or_else(|| DownloadErr::ArtistMapErr(artist.clone()))?;
+        let artist_config = &self.config.artists[&artist];
+        let saved = artist_config.saved_content();
+        let download_dir = get_download_dir(artist_config, &post_type);
 
         // set up loop variables
         let mut from = String::from("");
@@ -209,8 +551,16 @@ impl<'a> Network {
         }
         .replace("{artist_id}", &artist_id.to_string()[..]);
 
+        // posts already in the download directory
+        let archived: HashSet<i64> = archive::post_dirs(&download_dir)
+            .iter()
+            .map(|p| p.id)
+            .collect();
+        let mut archived_in_a_row = 0usize;
+        let mut stopped_early = false;
+
         // return value
-        let mut ret: Vec<Post> = Vec::new();
+        let mut post_ids: Vec<i64> = Vec::new();
 
         loop {
             // build request
@@ -226,6 +576,7 @@ impl<'a> Network {
             let params = [("pageSize", &page_size), ("from", &from)];
 
             // send request
+            let permit = self.limits.api(&url).await;
             let resp = self
                 .client
                 .get(&url)
@@ -239,65 +590,165 @@ impl<'a> Network {
                 .text()
                 .await
                 .map_err(|e| DownloadErr::ResponseTextErr(url.clone(), e))?;
+            std::mem::drop(permit);
             let posts_resp: Posts = serde_json::from_str(text)
                 .map_err(|e| DownloadErr::ResponseJsonErr(url.clone(), e))?;
             let posts = &posts_resp.posts;
             let num_posts = isize::try_from(posts.len()).unwrap();
 
-            // add to return vector
-            ret.extend(posts_resp.posts);
+            // queue posts for download
+            for post in posts_resp.posts {
+                post_ids.push(post.id);
+
+                // posts are newest first, so everything after this is too old
+                let date = config::post_date(&post.created_at);
+                if matches!(date, Some(d) if range.is_before(d)) {
+                    stopped_early = true;
+                    break;
+                }
+                if matches!(date, Some(d) if range.is_after(d)) {
+                    continue;
+                }
+                if !artist_config.includes_member(&post.community_user.nickname) {
+                    continue;
+                }
+                if let Some(kinds) = &artist_config.keep_posts_with {
+                    if !kinds.iter().any(|&k| post.has_content(k)) {
+                        continue;
+                    }
+                }
+
+                // filtered out posts and posts with nothing to save are never
+                // archived, so only the others count towards stopping early
+                let nothing_to_save =
+                    post.media_urls(&saved).is_empty() && !saved.contains(&ContentKind::Text);
+                if archived.contains(&post.id) {
+                    archived_in_a_row += 1;
+                } else if !nothing_to_save {
+                    archived_in_a_row = 0;
+                }
+
+                if queue.send(post).await.is_err() {
+                    break;
+                }
+            }
 
             // determine if we need to keep looping
-            if posts_resp.is_ended {
+            if posts_resp.is_ended || stopped_early {
                 break;
             }
-            match recent {
-                Some(v) => {
-                    count += num_posts;
-                    if v - count <= 0 {
-                        break;
-                    }
+            if let Some(n) = stop_after {
+                if archived_in_a_row >= n {
+                    stopped_early = true;
+                    break;
+                }
+            }
+            if let Some(v) = recent {
+                count += num_posts;
+                if v - count <= 0 {
+                    break;
                 }
-                None => (),
             }
             from = posts_resp
                 .last_id
                 .ok_or(DownloadErr::LastIdErr)?
                 .to_string();
         }
-        Ok(ret)
+        Ok(Crawl {
+            download_dir,
+            complete: recent.is_none() && !stopped_early,
+            post_ids,
+        })
     }
 
-    async fn download_post(
+    // where a post would be saved and what would be fetched for it, or None
+    // if download_post would skip it
+    async fn plan_post(
         &self,
         mut post: Post,
-        mtx: Arc<Mutex<usize>>,
-    ) -> Result<DownloadOk, DownloadErr> {
+        details: Details,
+    ) -> Result<Option<Planned>, DownloadErr> {
         let prefix = get_prefix(&post);
 
         // create download directory
         let artist = post.community.name.to_lowercase();
         let artist_config = &self.config.artists.get(&artist).unwrap();
-        let download_dir = match post.post_type.to_lowercase().as_str() {
-            "normal" => artist_config.artist_download_path.clone(),
-            "to_fans" => artist_config.moments_download_path.clone(),
-            _ => return Err(DownloadErr::ParsePostTypeErr(post.post_type.to_string())),
-        }
-        .unwrap_or_else(|| String::from("posts"));
+        let post_type = post.kind()?;
+        let base_dir = get_download_dir(artist_config, &post_type);
+        let download_dir = if artist_config.member_subdirs {
+            let member = sanitize_filename::sanitize(&post.community_user.nickname);
+            format!("{}/{}", base_dir, member)
+        } else {
+            base_dir.clone()
+        };
         let dir = format!("{}/{}", download_dir, prefix);
-        let temp_dir = format!("{}.temp", dir);
 
         // don't download if directory exists
         if fs::metadata(dir.as_str()).is_ok() {
-            return Ok(DownloadOk::Skipped(post.clone()));
+            return Ok(None);
+        }
+        if post_dir_exists(&download_dir, &prefix) || post_dir_exists(&base_dir, &prefix) {
+            return Ok(None);
         }
-        if post_dir_exists(download_dir, &prefix) {
-            return Ok(DownloadOk::Skipped(post.clone()));
+
+        // details give the content of locked posts and video urls, only get
+        // them if they can add something to save
+        let saved = artist_config.saved_content();
+        let wants_videos = post.attached_videos.is_some() && saved.contains(&ContentKind::Videos);
+        let nothing_to_save =
+            |post: &Post| post.media_urls(&saved).is_empty() && !saved.contains(&ContentKind::Text);
+        if !post.locked && !wants_videos && nothing_to_save(&post) {
+            return Ok(None);
+        }
+
+        match details {
+            Details::Fetch(mtx) if post.locked || wants_videos => {
+                post = self.download_post_info(&post, mtx).await?;
+            }
+            Details::Unlocked if !post.locked && wants_videos => {
+                post = self
+                    .download_post_info(&post, Arc::new(Mutex::new(0)))
+                    .await?;
+            }
+            _ => (),
         }
 
-        if post.locked || post.attached_videos.is_some() {
-            post = self.download_post_info(&post, mtx).await?;
+        // skip posts with nothing left to save
+        if nothing_to_save(&post) {
+            return Ok(None);
         }
+        let media_urls = post.media_urls(&saved);
+
+        Ok(Some(Planned {
+            post,
+            prefix,
+            dir,
+            saved,
+            media_urls,
+        }))
+    }
+
+    async fn download_post(
+        &self,
+        post: Post,
+        mtx: Arc<Mutex<usize>>,
+    ) -> Result<DownloadOk, DownloadErr> {
+        match self.plan_post(post.clone(), Details::Fetch(mtx)).await? {
+            Some(planned) => self.save_post(planned).await,
+            None => Ok(DownloadOk::Skipped(post)),
+        }
+    }
+
+    // download everything planned for a post into its directory
+    async fn save_post(&self, planned: Planned) -> Result<DownloadOk, DownloadErr> {
+        let Planned {
+            post,
+            prefix,
+            dir,
+            saved,
+            media_urls,
+        } = planned;
+        let temp_dir = format!("{}.temp", dir);
 
         // recreate temp directory
         let _ = fs::remove_dir_all(&temp_dir);
@@ -310,58 +761,68 @@ impl<'a> Network {
             )
         });
 
-        // download photos
-        if let Some(photos) = &post.photos {
-            for (i, photo) in photos.iter().enumerate() {
-                let ext = match photo.url.rfind('.') {
-                    Some(ext_idx) => &photo.url[ext_idx..],
-                    None => "",
-                };
-                let save_path = format!("{}/{}-img{:02}{}", temp_dir, prefix, i, ext);
-                self.download_direct(&photo.url, &save_path).await?
+        // download photos and videos
+        let mut files = stream::iter(media_urls)
+            .map(|(kind, i, url)| {
+                let temp_dir = &temp_dir;
+                let prefix = &prefix;
+                async move { self.download_media(&url, temp_dir, prefix, kind, i).await }
+            })
+            .buffered(self.config.media_connections())
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        set_video_info(&post, &mut files);
+        let mut new_stored = Vec::new();
+        match Store::new(&self.config) {
+            Some(store) => {
+                for file in files.iter_mut() {
+                    let embed = self.config.embed_metadata;
+                    new_stored.extend(store_file(&store, embed, &temp_dir, &dir, &post, file)?);
+                }
             }
-        }
-
-        // download videos
-        if let Some(videos) = &post.attached_videos {
-            for (i, video) in videos.iter().enumerate() {
-                if let Some(video_url) = &video.video_url {
-                    let ext = match video_url.rfind('.') {
-                        Some(ext_idx) => &video_url[ext_idx..],
-                        None => "",
-                    };
-                    let save_path = format!("{}/{}-vid{:02}{}", temp_dir, prefix, i, ext);
-                    self.download_direct(&video_url, &save_path).await?;
+            None if self.config.embed_metadata => {
+                for file in files.iter_mut() {
+                    embed_metadata(&temp_dir, &post, file)?;
                 }
             }
+            None => (),
         }
 
         // write contents
+        if saved.contains(&ContentKind::Text) {
+            write_content(&temp_dir, &prefix, &post)?;
+        }
+
+        // write metadata
         {
-            let save_path = format!("{}/{}-content.txt", temp_dir, prefix);
-            let body = match &post.body {
-                Some(v) => v.as_str(),
-                None => "",
+            let save_path = format!("{}/{}-metadata.json", temp_dir, prefix);
+            let post_metadata = PostMetadata {
+                post: post.clone(),
+                saved,
+                files,
+                archived_at: Utc::now().to_rfc3339(),
+                deleted_upstream_at: None,
             };
-            let content = format!(
-                "https://weverse.io/{}/artist/{}\n{} ({}):\n{}",
-                post.community.name.to_lowercase(),
-                post.id.to_string(),
-                &post.community_user.nickname,
-                &post.created_at,
-                body
-            );
-            let mut buffer = File::create(save_path.as_str())
-                .map_err(|e| DownloadErr::FileCreateErr(save_path.clone(), e))?;
-            buffer
-                .write_all(&content.as_bytes())
-                .map_err(|e| DownloadErr::FileWriteErr(save_path.clone(), e))?;
+            metadata::write(&save_path, &post_metadata).map_err(DownloadErr::MetadataErr)?;
         }
 
         // rename temp directory
-        let _ = fs::rename(&temp_dir, &dir).map_err(|e| DownloadErr::RenameErr(temp_dir, e))?;
+        fs::rename(&temp_dir, &dir).map_err(|e| DownloadErr::RenameErr(temp_dir, e))?;
+        if let Some(time) = touch::post_time(&post, self.config.mtime) {
+            touch::stamp(&dir, time)
+                .map_err(|e| DownloadErr::MetadataErr(format!("{}: {}", dir, e)))?;
+            // files new to the store take the time of the first post with them
+            for stored in new_stored {
+                touch::stamp_file(&stored, time).map_err(|e| {
+                    DownloadErr::MetadataErr(format!("{}: {}", stored.display(), e))
+                })?;
+            }
+        }
 
-        Ok(DownloadOk::Downloaded(post.clone()))
+        Ok(DownloadOk::Downloaded(post.clone(), dir))
     }
 
     async fn download_post_info(
@@ -373,22 +834,27 @@ impl<'a> Network {
             .replace("{artist_id}", post.community.id.to_string().as_str())
             .replace("{post_id}", post.id.to_string().as_str());
 
-        let resp = if post.locked {
-            let guard = mtx.lock().unwrap();
+        let mut password = None;
+        if post.locked {
+            let guard = mtx.lock().await;
             // query user for password
             let shown_url = POST_URL
                 .replace("{artist}", post.community.name.to_string().as_str())
                 .replace("{post_id}", post.id.to_string().as_str());
-            std::io::stdout().lock();
             println!("Password required for {}:", shown_url);
-            let password = io::AsyncBufReadExt::lines(io::BufReader::new(io::stdin()))
-                .next_line()
-                .await
-                .map_err(|e| DownloadErr::StdinErrStr(e))?
-                .ok_or(DownloadErr::StdinErr)?;
+            password = Some(
+                io::AsyncBufReadExt::lines(io::BufReader::new(io::stdin()))
+                    .next_line()
+                    .await
+                    .map_err(DownloadErr::StdinErrStr)?
+                    .ok_or(DownloadErr::StdinErr)?,
+            );
 
             std::mem::drop(guard);
+        }
 
+        let permit = self.limits.api(&url).await;
+        let resp = if let Some(password) = password {
             let json: HashMap<&str, &str> = [("lockPassword", password.as_str())]
                 .iter()
                 .cloned()
@@ -412,7 +878,7 @@ impl<'a> Network {
         if !resp.status().is_success() {
             return Err(DownloadErr::ResponseErr(
                 url.clone(),
-                post.clone(),
+                Some(Box::new(post.clone())),
                 resp.status(),
             ));
         }
@@ -421,26 +887,137 @@ impl<'a> Network {
             .text()
             .await
             .map_err(|e| DownloadErr::ResponseTextErr(url.clone(), e))?;
+        std::mem::drop(permit);
         let post_resp: Post =
             serde_json::from_str(text).map_err(|e| DownloadErr::ResponseJsonErr(url.clone(), e))?;
 
         Ok(post_resp)
     }
 
-    async fn download_direct(&self, url: &str, save_path: &str) -> Result<(), DownloadErr> {
-        let data = self
+    async fn download_media(
+        &self,
+        url: &str,
+        dir: &str,
+        prefix: &str,
+        kind: FileKind,
+        index: usize,
+    ) -> Result<MediaFile, DownloadErr> {
+        let stem = metadata::file_stem(prefix, kind, index);
+        let save_stem = format!("{}/{}", dir, stem);
+        let (ext, size, sha256, variant) = if hls::is_playlist(url) {
+            let (ext, size, sha256) = self.download_hls(url, &save_stem).await?;
+            (ext, size, sha256, None)
+        } else if kind == FileKind::Video {
+            let (ext, size, sha256) = self.download_direct(url, &save_stem).await?;
+            (ext, size, sha256, None)
+        } else {
+            // images may have a higher resolution variant than the given url
+            let variant = self.best_variant(url).await;
+            let (ext, size, sha256) = self.download_direct(&variant, &save_stem).await?;
+            (ext, size, sha256, Some(variant).filter(|v| v != url))
+        };
+
+        // record image dimensions from the downloaded file
+        let name = format!("{}{}", stem, ext);
+        let (width, height) = match kind {
+            FileKind::Video => (None, None),
+            _ => {
+                let mut head = Vec::new();
+                let _ = File::open(format!("{}/{}", dir, name))
+                    .and_then(|f| f.take(media::HEADER_LEN).read_to_end(&mut head));
+                match media::dimensions(&head) {
+                    Some((w, h)) => (Some(w as i64), Some(h as i64)),
+                    None => (None, None),
+                }
+            }
+        };
+
+        Ok(MediaFile {
+            kind,
+            index,
+            name,
+            url: url.to_string(),
+            variant,
+            size,
+            sha256,
+            stored: None,
+            duplicate_of: None,
+            width,
+            height,
+            duration: None,
+        })
+    }
+
+    // download a file to save_stem plus the returned extension, which is
+    // decided from the file contents, then the Content-Type, then the url
+    async fn download_direct(
+        &self,
+        url: &str,
+        save_stem: &str,
+    ) -> Result<(String, u64, String), DownloadErr> {
+        let _permit = self.limits.media(url).await;
+        let mut resp = self
             .anon_client
             .get(url)
             .send()
             .await
-            .map_err(|e| DownloadErr::RequestErr(url.to_string(), e))?
-            .bytes()
+            .map_err(|e| DownloadErr::RequestErr(url.to_string(), e))?;
+        // error pages would otherwise be saved as media
+        if !resp.status().is_success() {
+            return Err(DownloadErr::ResponseErr(
+                url.to_string(),
+                None,
+                resp.status(),
+            ));
+        }
+        let expected_len = resp.content_length();
+        let content_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        // stream to disk, hashing as we go
+        let part_path = format!("{}.part", save_stem);
+        let mut buffer = File::create(&part_path)
+            .map_err(|e| DownloadErr::FileCreateErr(part_path.clone(), e))?;
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        let mut head = Vec::new();
+        while let Some(chunk) = resp
+            .chunk()
             .await
-            .map_err(|e| DownloadErr::ResponseBytesErr(url.to_string(), e))?;
-        let mut buffer = File::create(save_path)
-            .map_err(|e| DownloadErr::FileCreateErr(save_path.to_string(), e))?;
-        buffer
-            .write_all(&data)
-            .map_err(|e| DownloadErr::FileWriteErr(save_path.to_string(), e))
+            .map_err(|e| DownloadErr::ResponseBytesErr(url.to_string(), e))?
+        {
+            self.limits.throttle(chunk.len()).await;
+            buffer
+                .write_all(&chunk)
+                .map_err(|e| DownloadErr::FileWriteErr(part_path.clone(), e))?;
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+            if head.len() < media::SNIFF_LEN {
+                let n = std::cmp::min(media::SNIFF_LEN - head.len(), chunk.len());
+                head.extend_from_slice(&chunk[..n]);
+            }
+        }
+
+        // don't keep empty or truncated responses around
+        if let Some(expected) = expected_len {
+            if expected != size {
+                return Err(DownloadErr::ResponseLengthErr(
+                    url.to_string(),
+                    expected,
+                    size,
+                ));
+            }
+        }
+        if size == 0 {
+            return Err(DownloadErr::EmptyResponseErr(url.to_string()));
+        }
+
+        let ext = media::extension(&head, content_type.as_deref(), url);
+        let save_path = format!("{}{}", save_stem, ext);
+        fs::rename(&part_path, &save_path).map_err(|e| DownloadErr::RenameErr(part_path, e))?;
+        Ok((ext, size, format!("{:x}", hasher.finalize())))
     }
 }
@@ -1,8 +1,11 @@
-use crate::config::Config;
-use serde::Deserialize;
+use crate::archive::metadata::FileKind;
+use crate::config::{Config, ContentKind};
+use crate::network::limits::Limits;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct Network {
@@ -10,6 +13,7 @@ pub struct Network {
     pub client: reqwest::Client,
     pub anon_client: reqwest::Client,
     pub artist_id_map: HashMap<String, i64>,
+    pub limits: Arc<Limits>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -21,7 +25,7 @@ pub struct Posts {
     pub last_id: Option<i64>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Post {
     pub id: i64,
     #[serde(rename = "communityUser")]
@@ -41,42 +45,119 @@ pub struct Post {
     pub attached_videos: Option<Vec<Video>>,
     #[serde(rename = "isLocked")]
     pub locked: bool,
+    #[serde(rename = "commentCount", default)]
+    pub comment_count: Option<i64>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Post {
+    pub fn kind(&self) -> Result<PostType, DownloadErr> {
+        match self.post_type.to_lowercase().as_str() {
+            "normal" => Ok(PostType::Artist),
+            "to_fans" => Ok(PostType::Moment),
+            _ => Err(DownloadErr::ParsePostTypeErr(self.post_type.to_string())),
+        }
+    }
+
+    // whether the post contains this kind of content, text meaning text only
+    pub fn has_content(&self, kind: ContentKind) -> bool {
+        let has_photos = self.photos.as_ref().is_some_and(|p| !p.is_empty());
+        let has_videos = self.attached_videos.as_ref().is_some_and(|v| !v.is_empty());
+        match kind {
+            ContentKind::Photos => has_photos,
+            ContentKind::Videos => has_videos,
+            ContentKind::Text => !has_photos && !has_videos,
+        }
+    }
+
+    // saved photo, video and video thumbnail urls in download order, along
+    // with their index
+    pub fn media_urls(&self, saved: &[ContentKind]) -> Vec<(FileKind, usize, String)> {
+        let photos = self
+            .photos
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(i, p)| (FileKind::Photo, i, p.url.clone()));
+        let videos = self
+            .attached_videos
+            .iter()
+            .flatten()
+            .enumerate()
+            .filter_map(|(i, v)| Some((FileKind::Video, i, v.video_url.clone()?)));
+        let thumbnails = self
+            .attached_videos
+            .iter()
+            .flatten()
+            .enumerate()
+            .filter_map(|(i, v)| Some((FileKind::Thumbnail, i, v.thumbnail_url.clone()?)));
+        photos
+            .chain(videos)
+            .chain(thumbnails)
+            .filter(|(kind, _, _)| saved.contains(&kind.content_kind()))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct CommunityUser {
     pub id: i64,
     #[serde(rename = "profileNickname")]
     pub nickname: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Community {
     pub id: i64,
     pub name: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Photo {
     pub id: i64,
     #[serde(rename = "orgImgUrl")]
     pub url: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Video {
     #[serde(rename = "videoUrl")]
     pub video_url: Option<String>,
+    #[serde(rename = "thumbnailImgUrl")]
+    pub thumbnail_url: Option<String>,
+    #[serde(rename = "videoWidth")]
+    pub width: Option<i64>,
+    #[serde(rename = "videoHeight")]
+    pub height: Option<i64>,
+    #[serde(rename = "length")]
+    pub duration: Option<f64>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PostType {
     Artist,
     Moment,
 }
 
+impl PostType {
+    // name used in file names, hooks and exports
+    pub fn name(self) -> &'static str {
+        match self {
+            PostType::Artist => "artist",
+            PostType::Moment => "moment",
+        }
+    }
+}
+
+// ids seen while paging through a feed, complete if the whole feed was seen
+pub struct Crawl {
+    pub download_dir: String,
+    pub complete: bool,
+    pub post_ids: Vec<i64>,
+}
+
 #[derive(Debug)]
 pub enum DownloadOk {
-    Downloaded(Post),
+    Downloaded(Post, String),
     Skipped(Post),
 }
 
@@ -86,7 +167,9 @@ pub enum DownloadErr {
     LastIdErr,
     ParsePostTypeErr(String),
     RequestErr(String, reqwest::Error),
-    ResponseErr(String, Post, reqwest::StatusCode),
+    ResponseErr(String, Option<Box<Post>>, reqwest::StatusCode),
+    ResponseLengthErr(String, u64, u64),
+    EmptyResponseErr(String),
     ResponseBytesErr(String, reqwest::Error),
     ResponseJsonErr(String, serde_json::Error),
     ResponseTextErr(String, reqwest::Error),
@@ -95,6 +178,8 @@ pub enum DownloadErr {
     FileCreateErr(String, std::io::Error),
     FileWriteErr(String, std::io::Error),
     RenameErr(String, std::io::Error),
+    MetadataErr(String),
+    HlsErr(String, String),
 }
 
 impl Error for DownloadErr {}
@@ -125,6 +210,12 @@ impl fmt::Display for DownloadErr {
             DownloadErr::ResponseErr(s, _, c) => {
                 format!("Error response for {}: {}", s, c).fmt(f)
             },
+            DownloadErr::ResponseLengthErr(s, expected, actual) => {
+                format!("Error response for {} was {} bytes, expected {}", s, actual, expected).fmt(f)
+            },
+            DownloadErr::EmptyResponseErr(s) => {
+                format!("Error response for {} was empty", s).fmt(f)
+            },
             DownloadErr::ResponseBytesErr(s, e) => {
                 format!("Error parsing bytes for {}: {}", s, e).fmt(f)
             },
@@ -137,6 +228,10 @@ impl fmt::Display for DownloadErr {
             DownloadErr::RenameErr(s, e) => {
                 format!("Error renaming {}: {}", s, e).fmt(f)
             },
+            DownloadErr::MetadataErr(s) => s.fmt(f),
+            DownloadErr::HlsErr(s, e) => {
+                format!("Error downloading stream {}: {}", s, e).fmt(f)
+            },
         }
     }
 }
@@ -0,0 +1,336 @@
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::archive;
+use crate::archive::metadata::{self, FileKind};
+use crate::config::{Config, WebhookConfig, WebhookFormat};
+use crate::network::get_url;
+use crate::network::network_structs::*;
+
+const WEBHOOK_ATTEMPTS: u32 = 3;
+const EXCERPT_LEN: usize = 200;
+// longest wait a Retry-After header can ask for
+const MAX_RETRY_WAIT_SECS: f64 = 60.0;
+
+// what a webhook is told about a newly downloaded post
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    pub artist: String,
+    pub author: String,
+    pub url: String,
+    pub excerpt: String,
+    pub created_at: String,
+    pub photos: usize,
+    pub videos: usize,
+    pub dir: String,
+    pub files: Vec<String>,
+}
+
+impl Notification {
+    pub fn new(post: &Post, dir: &str) -> Self {
+        let body = post.body.as_deref().unwrap_or_default().trim();
+        let excerpt = match body.char_indices().nth(EXCERPT_LEN) {
+            Some((i, _)) => format!("{}…", &body[..i]),
+            None => body.to_string(),
+        };
+
+        // local files from the metadata written alongside the post
+        let files = metadata::read(archive::metadata_path(dir))
+            .ok()
+            .flatten()
+            .map(|m| m.files)
+            .unwrap_or_default();
+        let count = |kind| files.iter().filter(|f| f.kind == kind).count();
+
+        Notification {
+            artist: post.community.name.to_lowercase(),
+            author: post.community_user.nickname.clone(),
+            url: get_url(post),
+            excerpt,
+            created_at: post.created_at.clone(),
+            photos: count(FileKind::Photo),
+            videos: count(FileKind::Video),
+            dir: dir.to_string(),
+            files: files
+                .iter()
+                .map(|f| format!("{}/{}", dir, f.name))
+                .collect(),
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} posted on Weverse ({} photos, {} videos)",
+            self.author, self.photos, self.videos
+        )
+    }
+
+    fn payload(&self, format: WebhookFormat) -> serde_json::Value {
+        match format {
+            WebhookFormat::Json => json!(self),
+            WebhookFormat::Discord => json!({
+                "content": self.summary(),
+                "embeds": [{
+                    "title": format!("{} ({})", self.author, self.artist),
+                    "url": self.url,
+                    "description": self.excerpt,
+                    "timestamp": self.created_at,
+                    "footer": { "text": self.dir },
+                }],
+            }),
+            WebhookFormat::Slack => json!({
+                "text": format!("{}\n<{}>\n{}", self.summary(), self.url, self.excerpt),
+            }),
+        }
+    }
+}
+
+// posts notifications about new posts to the configured webhooks
+#[derive(Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+    webhooks: Arc<Vec<WebhookConfig>>,
+}
+
+impl Notifier {
+    pub fn new(config: &Config) -> Option<Notifier> {
+        if config.webhooks.is_empty() {
+            return None;
+        }
+        Some(Notifier {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .ok()?,
+            webhooks: Arc::new(config.webhooks.clone()),
+        })
+    }
+
+    /// Send a notification to every webhook interested in its artist,
+    /// returning the errors
+    pub async fn notify(&self, notification: &Notification) -> Vec<String> {
+        let mut errors = Vec::new();
+        for webhook in self.webhooks.iter() {
+            let wanted = webhook.artists.as_ref().is_none_or(|artists| {
+                artists
+                    .iter()
+                    .any(|a| a.eq_ignore_ascii_case(&notification.artist))
+            });
+            if !wanted {
+                continue;
+            }
+            if let Err(e) = self.send(webhook, notification).await {
+                errors.push(format!("Error notifying {}: {}", webhook.url, e));
+            }
+        }
+        errors
+    }
+
+    async fn send(
+        &self,
+        webhook: &WebhookConfig,
+        notification: &Notification,
+    ) -> Result<(), String> {
+        let payload = notification.payload(webhook.format);
+        let mut attempt = 1;
+        loop {
+            let (retry, error, wait) =
+                match self.client.post(&webhook.url).json(&payload).send().await {
+                    Ok(resp) if resp.status().is_success() => return Ok(()),
+                    Ok(resp) => {
+                        let status = resp.status();
+                        // rate limited responses say how long to wait
+                        let wait = resp
+                            .headers()
+                            .get(reqwest::header::RETRY_AFTER)
+                            .and_then(|v| v.to_str().ok())
+                            .and_then(|v| v.parse::<f64>().ok())
+                            .filter(|secs| *secs >= 0.0)
+                            .map(|secs| Duration::from_secs_f64(secs.min(MAX_RETRY_WAIT_SECS)));
+                        let retry = status.is_server_error()
+                            || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                        (retry, format!("response {}", status), wait)
+                    }
+                    Err(e) => (true, e.to_string(), None),
+                };
+            if !retry || attempt >= WEBHOOK_ATTEMPTS {
+                return Err(error);
+            }
+            let wait = wait.unwrap_or_else(|| Duration::from_secs(1 << attempt));
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Send a sample notification to every configured webhook
+pub async fn test_webhooks(conf: &Config) -> Result<(), String> {
+    let notifier = Notifier::new(conf).ok_or("No webhooks configured")?;
+    let notification = Notification {
+        artist: String::from("weverse"),
+        author: String::from("download-weverse"),
+        url: String::from("https://weverse.io/"),
+        excerpt: String::from("This is a test notification"),
+        created_at: chrono::Utc::now().to_rfc3339(),
+        photos: 1,
+        videos: 0,
+        dir: String::from("posts/example"),
+        files: vec![String::from("posts/example/example-img00.jpg")],
+    };
+
+    let mut failed = 0usize;
+    for webhook in notifier.webhooks.iter() {
+        match notifier.send(webhook, &notification).await {
+            Ok(()) => println!("Sent test notification to {}", webhook.url),
+            Err(e) => {
+                failed += 1;
+                println!("Error notifying {}: {}", webhook.url, e);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} webhooks failed", failed));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // body of one HTTP request
+    async fn read_request(socket: &mut TcpStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        let header_end = loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed before the request ended");
+            data.extend_from_slice(&buf[..n]);
+            if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+        let headers = String::from_utf8_lossy(&data[..header_end]).to_lowercase();
+        let len: usize = headers
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length:"))
+            .map_or(0, |l| l.trim().parse().unwrap());
+        while data.len() < header_end + len {
+            let n = socket.read(&mut buf).await.unwrap();
+            data.extend_from_slice(&buf[..n]);
+        }
+        data[header_end..].to_vec()
+    }
+
+    // listener answering one request per status, recording the json bodies
+    async fn listen(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let received = bodies.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let body = read_request(&mut socket).await;
+                received
+                    .lock()
+                    .unwrap()
+                    .push(serde_json::from_slice(&body).unwrap());
+                let response = format!(
+                    "HTTP/1.1 {} Test\r\nContent-Length: 0\r\nRetry-After: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, bodies)
+    }
+
+    fn notifier(webhooks: Vec<(&str, WebhookFormat)>) -> Notifier {
+        Notifier {
+            client: reqwest::Client::new(),
+            webhooks: Arc::new(
+                webhooks
+                    .into_iter()
+                    .map(|(url, format)| WebhookConfig {
+                        url: url.to_string(),
+                        format,
+                        artists: None,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    fn notification() -> Notification {
+        Notification {
+            artist: String::from("bts"),
+            author: String::from("RM"),
+            url: String::from("https://weverse.io/bts/artist/1234"),
+            excerpt: String::from("hello"),
+            created_at: String::from("2021-01-02T03:04:05+09:00"),
+            photos: 2,
+            videos: 1,
+            dir: String::from("posts/20210102-1234-RM"),
+            files: vec![String::from(
+                "posts/20210102-1234-RM/20210102-1234-RM-img00.jpg",
+            )],
+        }
+    }
+
+    #[tokio::test]
+    async fn discord_and_slack_payloads() {
+        let (url, bodies) = listen(vec![204, 204]).await;
+        let notifier = notifier(vec![
+            (&url, WebhookFormat::Discord),
+            (&url, WebhookFormat::Slack),
+        ]);
+        let errors = notifier.notify(&notification()).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 2);
+        let discord = &bodies[0];
+        assert_eq!(
+            discord["content"],
+            "RM posted on Weverse (2 photos, 1 videos)"
+        );
+        assert_eq!(discord["embeds"][0]["title"], "RM (bts)");
+        assert_eq!(
+            discord["embeds"][0]["url"],
+            "https://weverse.io/bts/artist/1234"
+        );
+        assert_eq!(discord["embeds"][0]["description"], "hello");
+        let slack = bodies[1]["text"].as_str().unwrap();
+        assert!(slack.contains("<https://weverse.io/bts/artist/1234>"));
+        assert!(slack.ends_with("hello"));
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let (url, bodies) = listen(vec![500, 204]).await;
+        let notifier = notifier(vec![(&url, WebhookFormat::Json)]);
+        let errors = notifier.notify(&notification()).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0], bodies[1]);
+        assert_eq!(bodies[1]["artist"], "bts");
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_repeated_errors() {
+        let (url, bodies) = listen(vec![500; WEBHOOK_ATTEMPTS as usize]).await;
+        let notifier = notifier(vec![(&url, WebhookFormat::Json)]);
+        let errors = notifier.notify(&notification()).await;
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("500"), "{}", errors[0]);
+        assert_eq!(bodies.lock().unwrap().len(), WEBHOOK_ATTEMPTS as usize);
+    }
+}
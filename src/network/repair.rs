@@ -0,0 +1,228 @@
+use chrono::Utc;
+use std::fs;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::archive::metadata::{self, FileKind, MediaFile, PostMetadata};
+use crate::archive::store::Store;
+use crate::archive::verify::{self, Problem};
+use crate::archive::{self, media, touch, ArchivedPost};
+use crate::config::{Config, ContentKind};
+use crate::network::network_structs::*;
+use crate::network::{embed_metadata, set_video_info, store_file, write_content};
+
+enum Repaired {
+    Fixed(usize),
+    DeletedUpstream,
+}
+
+pub async fn repair(conf: &Config, token: &str) -> Result<(), String> {
+    println!("Getting artist ids...");
+    let n = Network::new(conf, token).await?;
+    let mtx = Arc::new(Mutex::new(0usize));
+
+    let mut fixed = 0usize;
+    let mut deleted = 0usize;
+    let mut failed = 0usize;
+    for post in archive::all_posts(conf) {
+        let problems = verify::check_post(&post);
+        if !problems.iter().any(Problem::is_broken) {
+            continue;
+        }
+
+        match n.repair_post(&post, mtx.clone()).await {
+            Ok(Repaired::Fixed(count)) => {
+                fixed += 1;
+                println!(
+                    "Repaired {} ({} files downloaded)",
+                    post.dir.display(),
+                    count
+                );
+            }
+            Ok(Repaired::DeletedUpstream) => {
+                deleted += 1;
+                println!(
+                    "{} was deleted upstream, keeping local files",
+                    post.dir.display()
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                println!("Error repairing {}: {}", post.dir.display(), e);
+            }
+        }
+    }
+
+    println!(
+        "Repaired {} posts, {} deleted upstream, {} failed",
+        fixed, deleted, failed
+    );
+    Ok(())
+}
+
+// build enough of a post to request its details from the url in content.txt
+fn legacy_stub(n: &Network, archived: &ArchivedPost) -> Result<Post, DownloadErr> {
+    let content_path = archived.content_path();
+    let content = fs::read_to_string(&content_path).unwrap_or_default();
+    let artist = content
+        .lines()
+        .next()
+        .and_then(|l| l.strip_prefix("https://weverse.io/"))
+        .and_then(|l| l.split('/').next())
+        .ok_or_else(|| {
+            DownloadErr::MetadataErr(format!(
+                "Error finding post url in {}",
+                content_path.display()
+            ))
+        })?;
+    let artist_id = *n
+        .artist_id_map
+        .get(artist)
+        .ok_or_else(|| DownloadErr::ArtistMapErr(artist.to_string()))?;
+
+    Ok(Post {
+        id: archived.id,
+        community: Community {
+            id: artist_id,
+            name: artist.to_string(),
+        },
+        ..Default::default()
+    })
+}
+
+// an existing file from before metadata was recorded, if it is intact
+fn legacy_file(
+    archived: &ArchivedPost,
+    kind: FileKind,
+    index: usize,
+    url: &str,
+) -> Option<MediaFile> {
+    let name = archived.find_file(&metadata::file_stem(&archived.prefix, kind, index))?;
+    let path = archived.dir.join(&name);
+    media::validate(&path).ok()?;
+    Some(MediaFile {
+        kind,
+        index,
+        name,
+        url: url.to_string(),
+        variant: None,
+        size: fs::metadata(&path).ok()?.len(),
+        sha256: media::sha256_file(&path).ok()?,
+        stored: None,
+        duplicate_of: None,
+        width: None,
+        height: None,
+        duration: None,
+    })
+}
+
+impl Network {
+    async fn repair_post(
+        &self,
+        archived: &ArchivedPost,
+        mtx: Arc<Mutex<usize>>,
+    ) -> Result<Repaired, DownloadErr> {
+        let old = archived.read_metadata().ok().flatten();
+        let saved = old
+            .as_ref()
+            .map_or_else(ContentKind::all, |m| m.saved.clone());
+        let mut stub = match &old {
+            Some(m) => m.post.clone(),
+            None => legacy_stub(self, archived)?,
+        };
+
+        // refetch the post so media urls are fresh
+        let post = match self.download_post_info(&stub, mtx.clone()).await {
+            Err(DownloadErr::ResponseErr(_, _, code))
+                if code == reqwest::StatusCode::FORBIDDEN && old.is_none() =>
+            {
+                // legacy posts don't record whether they are locked
+                stub.locked = true;
+                self.download_post_info(&stub, mtx).await
+            }
+            r => r,
+        };
+        let post = match post {
+            Ok(p) => p,
+            Err(DownloadErr::ResponseErr(_, _, code)) if code == reqwest::StatusCode::NOT_FOUND => {
+                if let Some(mut m) = old {
+                    if m.deleted_upstream_at.is_none() {
+                        m.deleted_upstream_at = Some(Utc::now().to_rfc3339());
+                        metadata::write(archived.metadata_path(), &m)
+                            .map_err(DownloadErr::MetadataErr)?;
+                    }
+                }
+                return Ok(Repaired::DeletedUpstream);
+            }
+            Err(e) => return Err(e),
+        };
+
+        // keep intact files, download everything else into place
+        let dir = archived.dir.to_string_lossy().to_string();
+        let mut files = Vec::new();
+        let mut downloaded = 0usize;
+        let store = Store::new(&self.config);
+        let mut new_stored = Vec::new();
+        for (kind, i, url) in post.media_urls(&saved) {
+            let existing = match old.as_ref().and_then(|m| m.file(kind, i)) {
+                Some(f) if verify::check_file(&archived.dir, f).is_none() => Some(f.clone()),
+                Some(_) => None,
+                None => legacy_file(archived, kind, i, &url),
+            };
+            let file = match existing {
+                Some(f) => f,
+                None => {
+                    let stem = metadata::file_stem(&archived.prefix, kind, i);
+                    if let Some(name) = archived.find_file(&stem) {
+                        let _ = fs::remove_file(archived.dir.join(name));
+                    }
+                    downloaded += 1;
+                    let mut file = self
+                        .download_media(&url, &dir, &archived.prefix, kind, i)
+                        .await?;
+                    let embed = self.config.embed_metadata;
+                    match &store {
+                        Some(store) => new_stored
+                            .extend(store_file(store, embed, &dir, &dir, &post, &mut file)?),
+                        None if embed => embed_metadata(&dir, &post, &mut file)?,
+                        None => (),
+                    }
+                    file
+                }
+            };
+            files.push(file);
+        }
+
+        set_video_info(&post, &mut files);
+
+        if saved.contains(&ContentKind::Text) && fs::metadata(archived.content_path()).is_err() {
+            write_content(&dir, &archived.prefix, &post)?;
+        }
+
+        let archived_at = match old {
+            Some(m) => m.archived_at,
+            None => Utc::now().to_rfc3339(),
+        };
+        let post_metadata = PostMetadata {
+            post,
+            saved,
+            files,
+            archived_at,
+            deleted_upstream_at: None,
+        };
+        metadata::write(archived.metadata_path(), &post_metadata)
+            .map_err(DownloadErr::MetadataErr)?;
+        if let Some(time) = touch::post_time(&post_metadata.post, self.config.mtime) {
+            touch::stamp(&archived.dir, time).map_err(|e| {
+                DownloadErr::MetadataErr(format!("{}: {}", archived.dir.display(), e))
+            })?;
+            for stored in new_stored {
+                touch::stamp_file(&stored, time).map_err(|e| {
+                    DownloadErr::MetadataErr(format!("{}: {}", stored.display(), e))
+                })?;
+            }
+        }
+
+        Ok(Repaired::Fixed(downloaded))
+    }
+}